@@ -0,0 +1,159 @@
+use crate::synth::SAMPLE_RATE;
+
+// Enough for a big chord with both hands
+const N_ARP_NOTES: usize = 16;
+
+/// Order the arpeggiator plays the held notes in (MIDI CC 47)
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+#[derive(Copy, Clone)]
+pub struct ArpNote {
+    pub note: u8,
+    pub channel: u8,
+    pub vel_amp: f32,
+}
+
+/// Step arpeggiator. While enabled, held notes are collected here instead of being
+/// played, and the synth plays one of them per step, a sixteenth note at `tempo_bpm`.
+/// Each arp note is released when the next one starts, or when every key is let go.
+pub struct Arpeggiator {
+    pub enabled: bool, // MIDI CC 46
+    pub mode: ArpMode, // MIDI CC 47
+    tempo_bpm: f32,    // MIDI CC 48
+    step_samples: u32,
+    notes: heapless::Vec<ArpNote, N_ARP_NOTES>, // held notes, sorted by pitch
+    index: usize,
+    descending: bool, // UpDown direction
+    rng: u32,
+    samples_until_step: u32,
+    sounding: Option<ArpNote>,
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        let mut arp = Self {
+            enabled: false,
+            mode: ArpMode::Up,
+            tempo_bpm: 0.0,
+            step_samples: 0,
+            notes: heapless::Vec::new(),
+            index: 0,
+            descending: false,
+            rng: 0x1234_5678,
+            samples_until_step: 0,
+            sounding: None,
+        };
+        arp.set_tempo(120.0);
+        arp
+    }
+
+    pub fn tempo_bpm(&self) -> f32 {
+        self.tempo_bpm
+    }
+
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.tempo_bpm = bpm;
+        self.step_samples = ((SAMPLE_RATE as f32 * 60.0) / (bpm * 4.0)) as u32;
+        self.samples_until_step = self.samples_until_step.min(self.step_samples);
+    }
+
+    /// Start the pattern again from the first note, on the next sample
+    pub fn restart(&mut self) {
+        self.index = 0;
+        self.descending = false;
+        self.samples_until_step = 0;
+    }
+
+    /// Add a held note. The first note of a new chord plays straight away.
+    pub fn note_on(&mut self, note: ArpNote) {
+        if let Some(pos) = self.notes.iter().position(|n| n.note == note.note) {
+            self.notes.remove(pos);
+        }
+        if self.notes.is_empty() {
+            self.index = 0;
+            self.descending = false;
+            self.samples_until_step = 0;
+        }
+        if self.notes.is_full() {
+            return;
+        }
+        let pos = self.notes.iter().position(|n| n.note > note.note);
+        let _ = self.notes.insert(pos.unwrap_or(self.notes.len()), note);
+    }
+
+    /// Remove a held note. Returns the sounding arp note if that was the last key, so
+    /// it can be released.
+    pub fn note_off(&mut self, note: u8) -> Option<ArpNote> {
+        if let Some(pos) = self.notes.iter().position(|n| n.note == note) {
+            self.notes.remove(pos);
+        }
+        if self.notes.is_empty() {
+            self.sounding.take()
+        } else {
+            None
+        }
+    }
+
+    /// Forget all held notes, returning the sounding arp note if any
+    pub fn clear(&mut self) -> Option<ArpNote> {
+        self.notes.clear();
+        self.sounding.take()
+    }
+
+    /// Samples until the next step, if there are notes to play
+    pub fn samples_until_step(&self) -> Option<u32> {
+        if self.enabled && !self.notes.is_empty() {
+            Some(self.samples_until_step)
+        } else {
+            None
+        }
+    }
+
+    pub fn advance(&mut self, samples: u32) {
+        self.samples_until_step = self.samples_until_step.saturating_sub(samples);
+    }
+
+    /// Move to the next note. Returns the note to release and the note to play.
+    pub fn step(&mut self) -> (Option<ArpNote>, Option<ArpNote>) {
+        self.samples_until_step = self.step_samples;
+        let n = self.notes.len();
+        if n == 0 {
+            return (self.sounding.take(), None);
+        }
+        let index = match self.mode {
+            ArpMode::Up => self.index % n,
+            ArpMode::Down => n - 1 - self.index % n,
+            ArpMode::UpDown => self.index.min(n - 1),
+            ArpMode::Random => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                self.rng as usize % n
+            }
+        };
+        self.index = match self.mode {
+            ArpMode::UpDown if n == 1 => 0,
+            ArpMode::UpDown => {
+                if index == n - 1 {
+                    self.descending = true;
+                } else if index == 0 {
+                    self.descending = false;
+                }
+                if self.descending {
+                    index - 1
+                } else {
+                    index + 1
+                }
+            }
+            _ => (self.index + 1) % n,
+        };
+        let note = self.notes[index];
+        (self.sounding.replace(note), Some(note))
+    }
+}
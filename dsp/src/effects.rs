@@ -0,0 +1,367 @@
+use micromath::F32Ext;
+#[cfg(not(test))]
+use static_cell::ConstStaticCell;
+
+use crate::synth::SAMPLE_RATE;
+
+// Maximum length of the master effects chain
+const N_EFFECTS: usize = 5;
+
+// Limiter threshold and knee width, relative to the synth's normalized mix, so loud
+// chords are turned down before they reach the soft clip. The attack is just slow
+// enough to let the start of a transient through, and the release long enough not to
+// pump.
+const LIMITER_THRESHOLD: f32 = 1.2;
+const LIMITER_KNEE: f32 = 0.4;
+const LIMITER_ATTACK_S: f32 = 0.001;
+const LIMITER_RELEASE_S: f32 = 0.1;
+
+// Soft clip knee and ceiling, relative to the synth's normalized mix. The ceiling is
+// just below where the output would clip.
+const SOFT_CLIP_KNEE: f32 = 1.0;
+const SOFT_CLIP_CEILING: f32 = 2.7;
+
+// Chorus delay line: 1024 f32 samples (4 KiB), ~21 ms at 48 kHz. That covers the
+// base delay plus the maximum sweep depth either side of it.
+const CHORUS_BUFFER_LEN: usize = 1024;
+const CHORUS_BASE_DELAY_S: f32 = 0.010;
+const CHORUS_MAX_DEPTH_S: f32 = 0.005;
+
+// Delay line: 0.5 s, ie. 24000 f32 samples (~94 KiB) at 48 kHz. That's far too big for
+// core1's stack, so it lives in a static rather than in the `Delay` itself.
+pub const DELAY_MAX_SAMPLES: usize = SAMPLE_RATE as usize / 2;
+#[cfg(not(test))]
+static DELAY_BUFFER: ConstStaticCell<[f32; DELAY_MAX_SAMPLES]> =
+    ConstStaticCell::new([0.0; DELAY_MAX_SAMPLES]);
+
+/// A master bus effect, processing one stereo frame at a time
+pub trait Effect {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32);
+
+    /// Clear any audio the effect is holding (delay lines, filter history), cutting
+    /// off its tail. Settings are kept.
+    fn reset(&mut self) {}
+}
+
+/// Stereo chorus: a short delay line swept by a sine LFO, with the left and right taps
+/// swept 90 degrees apart for width
+pub struct Chorus {
+    buffer: [f32; CHORUS_BUFFER_LEN],
+    write_pos: usize,
+    lfo_phase: f32,
+    pub depth: f32,   // 0.0 to 1.0, fraction of CHORUS_MAX_DEPTH_S
+    pub rate_hz: f32, // LFO rate
+    pub mix: f32,     // 0.0 (dry) to 1.0 (wet)
+}
+
+impl Chorus {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0.0; CHORUS_BUFFER_LEN],
+            write_pos: 0,
+            lfo_phase: 0.0,
+            depth: 0.5,
+            rate_hz: 0.8,
+            mix: 0.0,
+        }
+    }
+
+    // Read `delay_samples` behind the write position, interpolating linearly
+    fn read(&self, delay_samples: f32) -> f32 {
+        let pos = (self.write_pos + CHORUS_BUFFER_LEN) as f32 - delay_samples;
+        let i = pos as usize;
+        let frac = pos - i as f32;
+        let a = self.buffer[i % CHORUS_BUFFER_LEN];
+        let b = self.buffer[(i + 1) % CHORUS_BUFFER_LEN];
+        a + (b - a) * frac
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        // Keep the delay line running while bypassed, so enabling it doesn't glitch
+        self.buffer[self.write_pos] = 0.5 * (l + r);
+
+        let (out_l, out_r) = if self.mix > 0.0 {
+            self.lfo_phase += self.rate_hz / (SAMPLE_RATE as f32);
+            if self.lfo_phase >= 1.0 {
+                self.lfo_phase -= 1.0;
+            }
+            let angle = 2.0 * core::f32::consts::PI * self.lfo_phase;
+            let sweep = self.depth * CHORUS_MAX_DEPTH_S;
+            let delay_l = (CHORUS_BASE_DELAY_S + sweep * angle.sin()) * SAMPLE_RATE as f32;
+            let delay_r = (CHORUS_BASE_DELAY_S + sweep * angle.cos()) * SAMPLE_RATE as f32;
+            let wet_l = self.read(delay_l);
+            let wet_r = self.read(delay_r);
+            (
+                l * (1.0 - self.mix) + wet_l * self.mix,
+                r * (1.0 - self.mix) + wet_r * self.mix,
+            )
+        } else {
+            (l, r)
+        };
+
+        self.write_pos = (self.write_pos + 1) % CHORUS_BUFFER_LEN;
+        (out_l, out_r)
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+/// Mono feedback delay (echo). The delayed signal is mixed into both channels.
+pub struct Delay {
+    buffer: &'static mut [f32; DELAY_MAX_SAMPLES],
+    write_pos: usize,
+    pub delay_samples: usize, // 1 to DELAY_MAX_SAMPLES
+    pub feedback: f32,        // 0.0 to <1.0
+    pub mix: f32,             // wet level, 0.0 to 1.0
+}
+
+impl Delay {
+    /// Panics if called more than once, as there's only one delay buffer
+    pub fn new() -> Self {
+        // The tests build a synth each, so they get a buffer each, leaked from the heap
+        #[cfg(test)]
+        let buffer = crate::testing::leak([0.0; DELAY_MAX_SAMPLES]);
+        #[cfg(not(test))]
+        let buffer = DELAY_BUFFER.take();
+        Self {
+            buffer,
+            write_pos: 0,
+            delay_samples: DELAY_MAX_SAMPLES / 2,
+            feedback: 0.4,
+            mix: 0.0,
+        }
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        if self.mix <= 0.0 && self.feedback <= 0.0 {
+            return (l, r);
+        }
+        let delay = self.delay_samples.clamp(1, DELAY_MAX_SAMPLES);
+        let read_pos = (self.write_pos + DELAY_MAX_SAMPLES - delay) % DELAY_MAX_SAMPLES;
+        let wet = self.buffer[read_pos];
+        self.buffer[self.write_pos] = 0.5 * (l + r) + wet * self.feedback;
+        self.write_pos = (self.write_pos + 1) % DELAY_MAX_SAMPLES;
+        (l + wet * self.mix, r + wet * self.mix)
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+/// One-pole DC-blocking high-pass, `y[n] = x[n] - x[n-1] + R * y[n-1]`, per channel.
+/// Corner frequency is ~38 Hz at 48 kHz.
+pub struct DcBlocker {
+    x1: [f32; 2],
+    y1: [f32; 2],
+}
+
+impl DcBlocker {
+    const R: f32 = 0.995;
+
+    pub const fn new() -> Self {
+        Self {
+            x1: [0.0; 2],
+            y1: [0.0; 2],
+        }
+    }
+}
+
+impl Effect for DcBlocker {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let mut out = [l, r];
+        for (ch, x) in out.iter_mut().enumerate() {
+            let y = *x - self.x1[ch] + Self::R * self.y1[ch];
+            self.x1[ch] = *x;
+            self.y1[ch] = y;
+            *x = y;
+        }
+        (out[0], out[1])
+    }
+
+    fn reset(&mut self) {
+        self.x1 = [0.0; 2];
+        self.y1 = [0.0; 2];
+    }
+}
+
+/// Peak limiter with a soft knee and no lookahead. It follows the louder channel's
+/// level, and scales both channels by the same gain so the stereo image holds. Below
+/// the knee it's transparent, and above it the level is held at the threshold.
+pub struct Limiter {
+    env: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            env: 0.0,
+            attack: 1.0 - (-1.0 / (LIMITER_ATTACK_S * SAMPLE_RATE as f32)).exp(),
+            release: 1.0 - (-1.0 / (LIMITER_RELEASE_S * SAMPLE_RATE as f32)).exp(),
+        }
+    }
+
+    // Gain for the followed level. The output level bends over quadratically through
+    // the knee, so the gain reduction has no corners.
+    #[inline]
+    fn gain(&self, level: f32) -> f32 {
+        let knee_start = LIMITER_THRESHOLD - 0.5 * LIMITER_KNEE;
+        if level <= knee_start {
+            1.0
+        } else if level < LIMITER_THRESHOLD + 0.5 * LIMITER_KNEE {
+            let over = level - knee_start;
+            (level - over * over / (2.0 * LIMITER_KNEE)) / level
+        } else {
+            LIMITER_THRESHOLD / level
+        }
+    }
+}
+
+impl Effect for Limiter {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let peak = l.abs().max(r.abs());
+        let rate = if peak > self.env {
+            self.attack
+        } else {
+            self.release
+        };
+        self.env += (peak - self.env) * rate;
+        let gain = self.gain(self.env);
+        (l * gain, r * gain)
+    }
+
+    fn reset(&mut self) {
+        self.env = 0.0;
+    }
+}
+
+/// Soft clipper: transparent up to `SOFT_CLIP_KNEE`, then bending smoothly towards
+/// `SOFT_CLIP_CEILING` instead of hard clipping
+pub struct SoftClip;
+
+impl SoftClip {
+    #[inline]
+    fn clip(x: f32) -> f32 {
+        let level = x.abs();
+        if level <= SOFT_CLIP_KNEE {
+            return x;
+        }
+        let over = level - SOFT_CLIP_KNEE;
+        let room = SOFT_CLIP_CEILING - SOFT_CLIP_KNEE;
+        (SOFT_CLIP_KNEE + room * over / (over + room)).copysign(x)
+    }
+}
+
+impl Effect for SoftClip {
+    fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        (Self::clip(l), Self::clip(r))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum EffectKind {
+    Chorus,
+    Delay,
+    DcBlocker,
+    Limiter,
+    SoftClip,
+}
+
+/// The master effects, run in `order`. Each effect keeps its own state, and is
+/// skipped (but keeps its settings) when it's not in the order.
+pub struct EffectChain {
+    pub chorus: Chorus,
+    pub delay: Delay,
+    pub dc_blocker: DcBlocker,
+    pub limiter: Limiter,
+    pub soft_clip: SoftClip,
+    order: heapless::Vec<EffectKind, N_EFFECTS>,
+}
+
+impl EffectChain {
+    pub fn new() -> Self {
+        let mut chain = Self {
+            chorus: Chorus::new(),
+            delay: Delay::new(),
+            dc_blocker: DcBlocker::new(),
+            limiter: Limiter::new(),
+            soft_clip: SoftClip,
+            order: heapless::Vec::new(),
+        };
+        // strip any DC offset (eg. from the saw) before it reaches the DAC, limit loud
+        // passages, and clip last to catch what the limiter's attack lets through
+        chain.set_order(&[
+            EffectKind::Chorus,
+            EffectKind::Delay,
+            EffectKind::DcBlocker,
+            EffectKind::Limiter,
+            EffectKind::SoftClip,
+        ]);
+        chain
+    }
+
+    /// Set which effects run, and in what order. Anything beyond `N_EFFECTS` is ignored.
+    pub fn set_order(&mut self, order: &[EffectKind]) {
+        self.order.clear();
+        for &kind in order.iter().take(N_EFFECTS) {
+            let _ = self.order.push(kind);
+        }
+    }
+}
+
+impl Effect for EffectChain {
+    fn process(&mut self, mut l: f32, mut r: f32) -> (f32, f32) {
+        for kind in self.order.iter() {
+            (l, r) = match kind {
+                EffectKind::Chorus => self.chorus.process(l, r),
+                EffectKind::Delay => self.delay.process(l, r),
+                EffectKind::DcBlocker => self.dc_blocker.process(l, r),
+                EffectKind::Limiter => self.limiter.process(l, r),
+                EffectKind::SoftClip => self.soft_clip.process(l, r),
+            };
+        }
+        (l, r)
+    }
+
+    // Resets every effect, including any not currently in the chain
+    fn reset(&mut self) {
+        self.chorus.reset();
+        self.delay.reset();
+        self.dc_blocker.reset();
+        self.limiter.reset();
+        self.soft_clip.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A constant offset under a signal settles out, leaving the signal centred on zero
+    #[test]
+    fn dc_blocker_removes_offset() {
+        let mut dc_blocker = DcBlocker::new();
+        // a square wave with a period of 100 samples
+        let signal = |i: usize| if i % 100 < 50 { 0.3 } else { -0.3 };
+        for i in 0..SAMPLE_RATE as usize {
+            dc_blocker.process(0.5 + signal(i), -0.25 + signal(i));
+        }
+        let (mut sum_l, mut sum_r) = (0.0, 0.0);
+        for i in 0..10_000 {
+            let (l, r) = dc_blocker.process(0.5 + signal(i), -0.25 + signal(i));
+            sum_l += l;
+            sum_r += r;
+        }
+        let (mean_l, mean_r) = (sum_l / 10_000.0, sum_r / 10_000.0);
+        assert!(mean_l.abs() < 1e-3, "left mean {mean_l}");
+        assert!(mean_r.abs() < 1e-3, "right mean {mean_r}");
+    }
+}
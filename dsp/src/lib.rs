@@ -0,0 +1,27 @@
+//! The synth engine: voices, filters, effects and the MIDI handling that drives them,
+//! plus MIDI parsing and SysEx. Nothing here touches the hardware, so it builds for the
+//! host as well as the RP2350, and the tests run with a plain `cargo test` in this
+//! directory.
+
+#![no_std]
+// micromath stands in for std's float maths on the RP2350. On the host std is in the
+// build whenever the tests are (critical-section's `std` feature links it), and its
+// inherent methods take precedence, so the imports go unused there.
+#![cfg_attr(not(target_os = "none"), allow(unused_imports))]
+// Most of the engine's types are built in consts and statics, with `const fn new()`,
+// and there's no sensible default for the others
+#![allow(clippy::new_without_default)]
+
+pub mod arp;
+pub mod effects;
+#[cfg(feature = "metering")]
+pub mod meter;
+pub mod midi_clock;
+pub mod midi_out;
+pub mod midi_parser;
+pub mod oscillator;
+pub mod synth;
+pub mod sysex;
+#[cfg(test)]
+mod testing;
+pub mod wavetable;
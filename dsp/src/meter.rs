@@ -0,0 +1,54 @@
+use defmt::info;
+use micromath::F32Ext;
+
+use crate::synth::SAMPLE_RATE;
+
+/// Output level meter, for setting up gain structure. Tracks the peak, RMS and number
+/// of clipped samples of the final output, and logs them about once a second.
+pub struct Meter {
+    peak: f32,
+    sum_sq: f32,
+    n_frames: u32,
+    clips: u32,
+}
+
+impl Meter {
+    pub const fn new() -> Self {
+        Self {
+            peak: 0.0,
+            sum_sq: 0.0,
+            n_frames: 0,
+            clips: 0,
+        }
+    }
+
+    /// Add a frame of output, scaled to i16 but not yet clamped
+    #[inline]
+    pub fn add(&mut self, l: f32, r: f32) {
+        for x in [l, r] {
+            let level = x.abs();
+            self.peak = self.peak.max(level);
+            self.sum_sq += x * x;
+            if level >= i16::MAX as f32 {
+                self.clips += 1;
+            }
+        }
+        self.n_frames += 1;
+    }
+
+    /// Log the levels and start again, if a second's worth of output has been added
+    pub fn report(&mut self) {
+        if self.n_frames < SAMPLE_RATE {
+            return;
+        }
+        let rms = (self.sum_sq / (2 * self.n_frames) as f32).sqrt();
+        info!(
+            "Output peak {}, RMS {} (full scale {}), {} clipped samples",
+            self.peak as i32,
+            rms as i32,
+            i16::MAX,
+            self.clips
+        );
+        *self = Self::new();
+    }
+}
@@ -0,0 +1,63 @@
+use crate::synth::SAMPLE_RATE;
+
+const CLOCKS_PER_QUARTER: u32 = 24;
+
+/// Follows incoming MIDI clock (0xF8) to work out the host's tempo, timing each
+/// quarter note against the synth's sample count. Start (0xFA) and Continue (0xFB)
+/// run the clock, Stop (0xFC) freezes anything synced to it.
+pub struct MidiClock {
+    pub stopped: bool,
+    ticks: u32,
+    quarter_start: Option<u32>, // sample count at the start of the current quarter note
+    tempo_bpm: Option<f32>,
+}
+
+impl MidiClock {
+    pub const fn new() -> Self {
+        Self {
+            stopped: false,
+            ticks: 0,
+            quarter_start: None,
+            tempo_bpm: None,
+        }
+    }
+
+    /// Tempo derived from the clock, once a full quarter note has been received
+    pub fn tempo_bpm(&self) -> Option<f32> {
+        self.tempo_bpm
+    }
+
+    /// Timing clock at sample count `now`. Returns the updated tempo at the end of
+    /// every quarter note.
+    pub fn tick(&mut self, now: u32) -> Option<f32> {
+        let Some(start) = self.quarter_start else {
+            self.quarter_start = Some(now);
+            self.ticks = 0;
+            return None;
+        };
+        self.ticks += 1;
+        if self.ticks < CLOCKS_PER_QUARTER {
+            return None;
+        }
+        self.ticks = 0;
+        self.quarter_start = Some(now);
+        let elapsed = now.wrapping_sub(start);
+        if elapsed == 0 {
+            return None;
+        }
+        // Clocks are only timed to the nearest buffer, so smooth out the jitter
+        let bpm = 60.0 * SAMPLE_RATE as f32 / elapsed as f32;
+        let bpm = match self.tempo_bpm {
+            Some(prev) => prev + 0.5 * (bpm - prev),
+            None => bpm,
+        };
+        self.tempo_bpm = Some(bpm);
+        self.tempo_bpm
+    }
+
+    pub fn start(&mut self) {
+        self.stopped = false;
+        self.ticks = 0;
+        self.quarter_start = None;
+    }
+}
@@ -0,0 +1,17 @@
+use crate::synth::MidiEvent;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+const MIDI_OUT_QUEUE_SIZE: usize = 64;
+
+/// Outgoing MIDI, sent by the USB task. Unlike the input queue this is a channel, so the
+/// USB task can wait on it, and anything on either core can send.
+pub static MIDI_OUT: Channel<CriticalSectionRawMutex, MidiEvent, MIDI_OUT_QUEUE_SIZE> =
+    Channel::new();
+
+/// Queue a message for the MIDI output. Never blocks, so it's fine to call from the
+/// audio core. If nothing is draining the queue (no USB device, or UART MIDI input) it
+/// fills up, and further messages are dropped.
+pub fn send(event: MidiEvent) {
+    let _ = MIDI_OUT.try_send(event);
+}
@@ -0,0 +1,174 @@
+use crate::synth::MidiEvent;
+use crate::sysex::SysexAssembler;
+
+/// A complete message from a `MidiParser`
+pub enum MidiMessage<'a> {
+    /// A channel message, or a real-time message (status 0xF8 to 0xFF, no data bytes)
+    Event(MidiEvent),
+    /// A SysEx message, from the 0xF0 to the 0xF7
+    Sysex(&'a [u8]),
+}
+
+/// Length of a MIDI message in bytes, including its status byte. SysEx has no fixed
+/// length, so it counts as just the 0xF0.
+pub fn message_len(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 3,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        _ => 1,
+    }
+}
+
+/// Turns a raw MIDI byte stream into messages, whether it comes from the serial input or
+/// unpacked from USB MIDI packets. It handles running status and real-time bytes
+/// interleaved mid-message, and collects SysEx with a `SysexAssembler`. System common
+/// messages are skipped.
+pub struct MidiParser {
+    status: u8, // running status, 0 if none
+    data: [u8; 2],
+    n_data: usize,
+    sysex: SysexAssembler,
+}
+
+impl MidiParser {
+    pub const fn new() -> Self {
+        Self {
+            status: 0,
+            data: [0; 2],
+            n_data: 0,
+            sysex: SysexAssembler::new(),
+        }
+    }
+
+    /// Add a byte, returning the message it completes, if any
+    pub fn feed(&mut self, byte: u8) -> Option<MidiMessage<'_>> {
+        match byte {
+            // Real-time messages can appear anywhere, even mid-SysEx, and don't affect
+            // running status
+            0xF8..=0xFF => Some(MidiMessage::Event(MidiEvent {
+                status: byte,
+                data1: 0,
+                data2: 0,
+            })),
+            0xF7 => {
+                self.status = 0;
+                self.n_data = 0;
+                self.sysex.feed(byte).map(MidiMessage::Sysex)
+            }
+            _ => {
+                // the assembler only completes a message on the 0xF7
+                let _ = self.sysex.feed(byte);
+                self.feed_event(byte).map(MidiMessage::Event)
+            }
+        }
+    }
+
+    // Add a byte that isn't real-time or the end of a SysEx, returning the channel message
+    // it completes, if any
+    fn feed_event(&mut self, byte: u8) -> Option<MidiEvent> {
+        match byte {
+            // System common / SysEx cancel running status
+            0xF0..=0xF7 => {
+                self.status = 0;
+                self.n_data = 0;
+                None
+            }
+            0x80..=0xEF => {
+                self.status = byte;
+                self.n_data = 0;
+                None
+            }
+            _ => {
+                if self.status == 0 {
+                    return None;
+                }
+                self.data[self.n_data] = byte;
+                self.n_data += 1;
+
+                let len = message_len(self.status) - 1;
+                if self.n_data < len {
+                    return None;
+                }
+                self.n_data = 0;
+                Some(MidiEvent {
+                    status: self.status,
+                    data1: self.data[0],
+                    data2: if len == 2 { self.data[1] } else { 0 },
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+
+    // Feed `bytes` to a parser, returning the messages it completes. Events come back as
+    // their status and two data bytes, SysEx as the whole message.
+    fn parse(bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut parser = MidiParser::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| match parser.feed(byte)? {
+                MidiMessage::Event(e) => Some(vec![e.status, e.data1, e.data2]),
+                MidiMessage::Sysex(msg) => Some(msg.to_vec()),
+            })
+            .collect()
+    }
+
+    // Data bytes after a complete message reuse its status
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 64, 100, 60, 0, 0xC0, 5, 6]),
+            [
+                vec![0x90, 60, 100],
+                vec![0x90, 64, 100],
+                vec![0x90, 60, 0],
+                vec![0xC0, 5, 0],
+                vec![0xC0, 6, 0],
+            ]
+        );
+    }
+
+    // A real-time byte comes out as soon as it arrives, without breaking up the message
+    // it lands in
+    #[test]
+    fn real_time_mid_message() {
+        assert_eq!(
+            parse(&[0x90, 60, 0xF8, 100, 0xFE, 64, 100]),
+            [
+                vec![0xF8, 0, 0],
+                vec![0x90, 60, 100],
+                vec![0xFE, 0, 0],
+                vec![0x90, 64, 100],
+            ]
+        );
+    }
+
+    // and the same inside a SysEx message
+    #[test]
+    fn real_time_mid_sysex() {
+        assert_eq!(
+            parse(&[0xF0, 0x7D, 0xF8, 0x4F, 0x01, 0xF7]),
+            [vec![0xF8, 0, 0], vec![0xF0, 0x7D, 0x4F, 0x01, 0xF7]]
+        );
+    }
+
+    // Data bytes with no status to go with them are dropped, including after a SysEx,
+    // which cancels running status
+    #[test]
+    fn data_without_status_dropped() {
+        assert!(parse(&[60, 100, 0x40]).is_empty());
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF0, 0x7D, 0xF7, 64, 100]),
+            [vec![0x90, 60, 100], vec![0xF0, 0x7D, 0xF7]]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use micromath::F32Ext;
+
+use crate::synth::SAMPLE_RATE;
+use crate::wavetable::wavetable;
+
+/// A waveform generator. `phase` is the position through the cycle (0.0 to 1.0) and
+/// `dt` the phase increment per sample, for waveforms that need to know their
+/// frequency (eg. to band limit their edges).
+pub trait Oscillator {
+    fn sample(&mut self, phase: f32, dt: f32) -> f32;
+}
+
+#[derive(Copy, Clone)]
+pub struct Sine;
+
+impl Oscillator for Sine {
+    #[inline]
+    fn sample(&mut self, phase: f32, _dt: f32) -> f32 {
+        let angle = 2.0 * core::f32::consts::PI * phase;
+        angle.sin()
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Square {
+    pub pulse_width: f32, // 0.05 to 0.95
+}
+
+impl Oscillator for Square {
+    #[inline]
+    fn sample(&mut self, phase: f32, _dt: f32) -> f32 {
+        if phase < self.pulse_width { 1.0 } else { -1.0 }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Sawtooth;
+
+impl Oscillator for Sawtooth {
+    #[inline]
+    fn sample(&mut self, phase: f32, _dt: f32) -> f32 {
+        2.0 * phase - 1.0
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Triangle;
+
+impl Oscillator for Triangle {
+    #[inline]
+    fn sample(&mut self, phase: f32, _dt: f32) -> f32 {
+        if phase < 0.5 {
+            4.0 * phase - 1.0
+        } else {
+            3.0 - 4.0 * phase
+        }
+    }
+}
+
+/// White noise, ignoring the phase
+#[derive(Copy, Clone)]
+pub struct Noise {
+    pub rng: u32, // never zero
+}
+
+impl Oscillator for Noise {
+    #[inline]
+    fn sample(&mut self, _phase: f32, _dt: f32) -> f32 {
+        white_noise(&mut self.rng)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Wavetable {
+    pub position: f32, // 0.0 to 1.0
+}
+
+impl Oscillator for Wavetable {
+    #[inline]
+    fn sample(&mut self, phase: f32, _dt: f32) -> f32 {
+        wavetable(self.position, phase)
+    }
+}
+
+/// Morphs smoothly through sine, triangle, sawtooth and square as `position` goes from
+/// 0.0 to 1.0, crossfading between the two neighbouring waveforms. The sine is shifted
+/// to line up with the triangle, so that step doesn't thin the sound out.
+#[derive(Copy, Clone)]
+pub struct Morph {
+    pub position: f32,    // 0.0 to 1.0
+    pub pulse_width: f32, // of the square, 0.05 to 0.95
+}
+
+impl Morph {
+    #[inline]
+    fn shape(&self, index: usize, phase: f32, dt: f32) -> f32 {
+        match index {
+            0 => -(2.0 * core::f32::consts::PI * phase).cos(),
+            1 => Triangle.sample(phase, dt),
+            2 => Sawtooth.sample(phase, dt),
+            _ => Square {
+                pulse_width: self.pulse_width,
+            }
+            .sample(phase, dt),
+        }
+    }
+}
+
+impl Oscillator for Morph {
+    #[inline]
+    fn sample(&mut self, phase: f32, dt: f32) -> f32 {
+        let x = self.position * 3.0;
+        let index = (x as usize).min(2);
+        let frac = x - index as f32;
+        let a = self.shape(index, phase, dt);
+        if frac > 0.0 {
+            a + (self.shape(index + 1, phase, dt) - a) * frac
+        } else {
+            a
+        }
+    }
+}
+
+/// Length of a plucked string's delay line, in samples: a period of 65.4 Hz (C2, MIDI
+/// note 36), plus the two samples the read either side of the delay needs. Lower notes
+/// play at 65.4 Hz. Samples are stored as i16, so each voice's string takes 1.5 KB of
+/// RAM, 23.5 KB for 16 voices (twice that with `preset-crossfade`, which keeps a copy of
+/// the voices).
+pub const PLUCK_LEN: usize = SAMPLE_RATE as usize * 100 / PLUCK_LOWEST_CENTIHZ + 2;
+const PLUCK_LOWEST_CENTIHZ: usize = 6541;
+// The lowpass in the string's feedback loop, 0.0 (none) to 1.0, and how much of the
+// signal goes round the loop each time. The lowpass also delays the loop by
+// damping / (1 - damping) samples, which is taken off the delay line's length.
+const PLUCK_DAMPING: f32 = 0.5;
+const PLUCK_LOWPASS_DELAY: f32 = PLUCK_DAMPING / (1.0 - PLUCK_DAMPING);
+const PLUCK_FEEDBACK: f32 = 0.996;
+
+/// Karplus-Strong plucked string: a delay line one period long, filled with noise when
+/// it's plucked and fed back through a one-pole lowpass, so the high harmonics die away
+/// first. Unlike the other oscillators it has state, too much to copy around in an
+/// `Osc`, so each voice has its own and `Osc::PluckedString` just selects it.
+#[derive(Copy, Clone)]
+pub struct PluckedString {
+    line: [i16; PLUCK_LEN],
+    pos: usize, // where the next sample is written
+    lowpass: f32,
+}
+
+impl PluckedString {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; PLUCK_LEN],
+            pos: 0,
+            lowpass: 0.0,
+        }
+    }
+
+    /// Fill the delay line with noise from `rng`, restarting the string
+    pub fn pluck(&mut self, rng: &mut u32) {
+        for s in self.line.iter_mut() {
+            *s = (white_noise(rng) * i16::MAX as f32) as i16;
+        }
+        self.lowpass = 0.0;
+    }
+
+    /// The string's next sample. `dt` is the phase increment per sample, as for an
+    /// `Oscillator`, and sets the length of the delay line, so bends and glides follow
+    /// it smoothly.
+    #[inline]
+    pub fn sample(&mut self, dt: f32) -> f32 {
+        if dt <= 0.0 {
+            return 0.0;
+        }
+        let delay = (1.0 / dt - PLUCK_LOWPASS_DELAY).clamp(1.0, (PLUCK_LEN - 2) as f32);
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+
+        // read between the two samples either side of the delay
+        let a = self.line[(self.pos + PLUCK_LEN - whole) % PLUCK_LEN] as f32;
+        let b = self.line[(self.pos + PLUCK_LEN - whole - 1) % PLUCK_LEN] as f32;
+        let delayed = a + (b - a) * frac;
+
+        self.lowpass = delayed + (self.lowpass - delayed) * PLUCK_DAMPING;
+        let out = self.lowpass * PLUCK_FEEDBACK;
+        self.line[self.pos] = out as i16;
+        self.pos = (self.pos + 1) % PLUCK_LEN;
+        out / i16::MAX as f32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+    Noise,
+    Wavetable,
+    Morph,
+    PluckedString,
+}
+
+/// A voice's oscillator: one of the above, dispatched with a `match` rather than `dyn`
+/// so it inlines into the render loop. To add a waveform, implement `Oscillator` for
+/// it and add it here and to `Waveform`.
+#[derive(Copy, Clone)]
+pub enum Osc {
+    Sine(Sine),
+    Square(Square),
+    Sawtooth(Sawtooth),
+    Triangle(Triangle),
+    Noise(Noise),
+    Wavetable(Wavetable),
+    Morph(Morph),
+    PluckedString, // rendered from the voice's `PluckedString`
+}
+
+impl Osc {
+    pub fn new(
+        waveform: Waveform,
+        pulse_width: f32,
+        wavetable_pos: f32,
+        morph_pos: f32,
+        seed: u32,
+    ) -> Self {
+        match waveform {
+            Waveform::Sine => Osc::Sine(Sine),
+            Waveform::Square => Osc::Square(Square { pulse_width }),
+            Waveform::Sawtooth => Osc::Sawtooth(Sawtooth),
+            Waveform::Triangle => Osc::Triangle(Triangle),
+            Waveform::Noise => Osc::Noise(Noise { rng: seed | 1 }),
+            Waveform::Wavetable => Osc::Wavetable(Wavetable {
+                position: wavetable_pos,
+            }),
+            Waveform::Morph => Osc::Morph(Morph {
+                position: morph_pos,
+                pulse_width,
+            }),
+            Waveform::PluckedString => Osc::PluckedString,
+        }
+    }
+}
+
+impl Oscillator for Osc {
+    #[inline]
+    fn sample(&mut self, phase: f32, dt: f32) -> f32 {
+        match self {
+            Osc::Sine(o) => o.sample(phase, dt),
+            Osc::Square(o) => o.sample(phase, dt),
+            Osc::Sawtooth(o) => o.sample(phase, dt),
+            Osc::Triangle(o) => o.sample(phase, dt),
+            Osc::Noise(o) => o.sample(phase, dt),
+            Osc::Wavetable(o) => o.sample(phase, dt),
+            Osc::Morph(o) => o.sample(phase, dt),
+            // the string is in the voice, which plays it instead
+            Osc::PluckedString => 0.0,
+        }
+    }
+}
+
+// xorshift32 white noise in -1.0..1.0, no float transcendentals needed
+#[inline]
+pub fn white_noise(state: &mut u32) -> f32 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    (x as i32) as f32 / 2_147_483_648.0
+}
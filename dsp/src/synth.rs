@@ -0,0 +1,3944 @@
+use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use micromath::F32Ext;
+
+use crate::arp::{ArpMode, ArpNote, Arpeggiator};
+use crate::effects::{DELAY_MAX_SAMPLES, Effect, EffectChain};
+#[cfg(feature = "metering")]
+use crate::meter::Meter;
+use crate::midi_clock::MidiClock;
+use crate::midi_out;
+use crate::oscillator::{Osc, Oscillator, PluckedString, Sine, Waveform, white_noise};
+
+use defmt::{debug, info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use heapless::spsc::{Producer, Queue};
+use static_cell::StaticCell;
+
+pub const MIDI_QUEUE_SIZE: usize = 256;
+pub static MIDI_QUEUE: StaticCell<Queue<MidiEvent, MIDI_QUEUE_SIZE>> = StaticCell::new();
+
+/// Number of MIDI events dropped because the queue was full
+pub static MIDI_EVENTS_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Length of the sound parameter block written by `Synth::save_params`. There's
+/// spare room at the end, so parameters can be added without changing its size.
+pub const PARAMS_LEN: usize = 256;
+/// Parameter block layout version. Bump it whenever the layout changes, so blocks
+/// saved by older firmware are ignored rather than loaded as garbage.
+pub const PARAMS_VERSION: u8 = 11;
+pub type Params = [u8; PARAMS_LEN];
+
+/// Parameter block to save to flash (MIDI CC 106), picked up by the flash writer on
+/// core 0 since erasing and programming flash is too slow for the audio core
+pub static SAVE_PARAMS: Signal<CriticalSectionRawMutex, Params> = Signal::new();
+
+/// Parameters from a SysEx dump, applied by the synth before its next buffer
+pub static LOAD_PARAMS: Signal<CriticalSectionRawMutex, Params> = Signal::new();
+/// Set when a SysEx dump request arrives. The synth answers with its parameters in
+/// `DUMP_PARAMS`, which the USB task sends as a SysEx dump.
+pub static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+pub static DUMP_PARAMS: Signal<CriticalSectionRawMutex, Params> = Signal::new();
+
+/// Set while any voice is sounding, as of the last buffer, for the activity LED
+pub static VOICES_SOUNDING: AtomicBool = AtomicBool::new(false);
+
+// Most MIDI events handled at the start of each buffer. Events take time away from the
+// render, so a burst (eg. a fast controller sweep) is spread over a few buffers rather
+// than risking an underrun. The trade-off is latency: events past the limit wait a
+// whole buffer. Normal playing comes nowhere near it, and USB MIDI input drops CCs
+// that don't change anything before they reach the queue.
+const MAX_EVENTS_PER_BUFFER: usize = 64;
+
+// Queue slots kept free for note offs, so a burst of other events can't leave notes hanging
+const NOTE_OFF_RESERVE: usize = 16;
+
+// Output sample rate, shared with the I2S output. Oscillator, envelope and filter
+// timings are all derived from it, so changing it here is enough.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+// Output words per stereo frame: both 16-bit samples packed in one word, or one word
+// per channel with 32-bit I2S output (the `i2s-32bit` cargo feature)
+#[cfg(not(feature = "i2s-32bit"))]
+pub const WORDS_PER_FRAME: usize = 1;
+#[cfg(feature = "i2s-32bit")]
+pub const WORDS_PER_FRAME: usize = 2;
+#[cfg(all(feature = "i2s-32bit", feature = "pwm-audio"))]
+compile_error!("PWM audio output is 16-bit only");
+
+// Audio output buffer length in samples, selected by the `buffer-*` cargo features. The
+// output is double buffered (see N_BUFFERS), so the latency is two buffers: ~5.3 ms at
+// 128 samples, ~10.7 ms at 256 and ~21.3 ms at 512. Shorter buffers leave less slack
+// for a slow render before the audio underruns. Without a `buffer-*` feature the length
+// follows the polyphony, as fewer voices render faster.
+#[cfg(any(
+    all(feature = "buffer-128", feature = "buffer-256"),
+    all(feature = "buffer-128", feature = "buffer-512"),
+    all(feature = "buffer-256", feature = "buffer-512")
+))]
+compile_error!("Only one buffer-* feature can be enabled");
+#[cfg(feature = "buffer-128")]
+pub const BUFFER_SIZE: usize = 128;
+#[cfg(feature = "buffer-256")]
+pub const BUFFER_SIZE: usize = 256;
+#[cfg(feature = "buffer-512")]
+pub const BUFFER_SIZE: usize = 512;
+#[cfg(not(any(feature = "buffer-128", feature = "buffer-256", feature = "buffer-512")))]
+pub const BUFFER_SIZE: usize = if N_VOICES <= 4 {
+    128
+} else if N_VOICES <= 8 {
+    256
+} else {
+    512
+};
+
+// Output buffers the DMA plays in turn. With the `triple-buffer` feature a render can run
+// up to a buffer late without an underrun, as there's another buffer ready to play, at
+// the cost of a buffer's more latency and RAM.
+#[cfg(not(feature = "triple-buffer"))]
+pub const N_BUFFERS: usize = 2;
+#[cfg(feature = "triple-buffer")]
+pub const N_BUFFERS: usize = 3;
+#[cfg(all(feature = "triple-buffer", feature = "pwm-audio"))]
+compile_error!("Triple buffering is only supported with I2S output");
+
+// Polyphony, selected by the `polyphony-*` cargo features
+#[cfg(all(feature = "polyphony-4", feature = "polyphony-8"))]
+compile_error!("Only one polyphony-* feature can be enabled");
+#[cfg(feature = "polyphony-4")]
+pub const N_VOICES: usize = 4;
+#[cfg(feature = "polyphony-8")]
+pub const N_VOICES: usize = 8;
+#[cfg(not(any(feature = "polyphony-4", feature = "polyphony-8")))]
+pub const N_VOICES: usize = 16;
+
+// Default ADSR values, also restored by Reset All Controllers (MIDI CC 121)
+const DEFAULT_ATTACK_TIME_S: f32 = 0.005; // 5 ms
+const DEFAULT_DECAY_TIME_S: f32 = 0.050; // 50 ms
+const DEFAULT_SUSTAIN_LEVEL: f32 = 0.2; // 20%
+const DEFAULT_RELEASE_TIME_S: f32 = 0.500; // 500 ms
+// Default filter values
+const DEFAULT_FILTER_CUTOFF: f32 = 0.84; // about 6 kHz
+const DEFAULT_FILTER_RESONANCE: f32 = 0.5; // Low resonance
+// Filter cutoff range. The cutoff control is exponential, so each step of CC 26 moves
+// the cutoff by the same musical interval.
+const FILTER_MIN_HZ: f32 = 20.0;
+const FILTER_MAX_HZ: f32 = 18_000.0;
+const TPT_MAX_CUTOFF: f32 = 0.49; // highest TPT filter cutoff, as a fraction of the sample rate
+
+// Built-in vibrato, depth controlled by the mod wheel (MIDI CC 1)
+const VIBRATO_RATE_HZ: f32 = 5.5;
+const VIBRATO_MAX_DEPTH_SEMITONES: f32 = 0.5; // depth at full mod wheel
+const LFO_KEYTRACK_REF_NOTE: u8 = 60; // note at which keytracking leaves the rate unchanged
+
+const FILTER_KEYTRACK_REF_NOTE: u8 = 60; // note at which filter keytracking has no effect
+const ENV_KEYTRACK_REF_NOTE: u8 = 60; // note at which envelope keytracking has no effect
+// Attack time multiplier for the softest notes at full velocity to attack, and divisor
+// for the hardest. Notes at half velocity keep the patch's attack time.
+const VEL_TO_ATTACK_RANGE: f32 = 4.0;
+
+const PITCH_BEND_RANGE_SEMITONES: f32 = 2.0; // default, changed with RPN 0
+const MAX_PITCH_BEND_RANGE_SEMITONES: u8 = 48;
+const N_CHANNELS: usize = 16;
+
+const TEST_SIGNAL_FREQ_HZ: f32 = 1000.0;
+const TEST_SIGNAL_FADE_S: f32 = 0.01; // fade in and out, so switching doesn't click
+
+const AFTERTOUCH_CUTOFF_DEPTH: f32 = 0.5; // cutoff offset at full pressure
+
+const EXP_ENV_THRESHOLD: f32 = 0.001; // -60 dB, relative to the note's peak level
+
+// Output level at full volume for a normalized mix of 1.0. The soft clipper lets
+// the mix overshoot up to 2.7 before it saturates, and 2.7 * 12000 still fits in an
+// i16, so this leaves about 9 dB of headroom rather than clipping hard.
+const MAX_AMPLITUDE: f32 = 12000.0;
+
+// Level of the L+R sum with the `mono-out` feature. -3 dB is the usual compromise
+// between centred sounds, which add up, and hard panned ones, which don't.
+#[cfg(feature = "mono-out")]
+const MONO_SUM_GAIN: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+// Time constant of the sustain level smoothing, so sweeping it doesn't zipper
+const SUSTAIN_SMOOTH_S: f32 = 0.005;
+
+// Time constant of the per-voice cutoff smoothing, and how close it has to get to the
+// target cutoff to snap to it
+const CUTOFF_SMOOTH_S: f32 = 0.002;
+const CUTOFF_SETTLED: f32 = 1e-4;
+
+// Time constant of the mix normalization following the number of sounding voices
+const MIX_NORM_SMOOTH_S: f32 = 0.02;
+
+const STEAL_FADE_S: f32 = 0.0015; // fade-out of a stolen voice before it's reused
+
+const N_HELD_NOTES: usize = 16; // note stack depth for mono mode
+const N_LATCHED_NOTES: usize = 16; // notes the latch holds, the oldest is let go first
+
+const FIXED_VELOCITY_AMP: f32 = 100.0 / 127.0; // note amplitude with a fixed velocity curve
+
+const DENORMAL_THRESHOLD: f32 = 1e-15;
+
+const PHASE_ONE: f32 = 4_294_967_296.0; // one cycle of a u32 oscillator phase
+
+// FM: the modulator's highest frequency ratio (MIDI CC 103, in steps of 0.5) and
+// modulation index (MIDI CC 104), and the default decay of its envelope (MIDI CC 105)
+const FM_MAX_RATIO: f32 = 8.0;
+const FM_MAX_INDEX: f32 = 10.0;
+const FM_DEFAULT_DECAY_S: f32 = 1.0;
+
+const MUTE_RAMP_S: f32 = 0.005; // mute/unmute and volume fade time, to avoid clicks
+
+// Length of the crossfade after a Program Change, with the `preset-crossfade` feature.
+// The voices are rendered twice while it runs, so it's kept short and fixed.
+#[cfg(feature = "preset-crossfade")]
+const PRESET_XFADE_FRAMES: usize = SAMPLE_RATE as usize * 5 / 1000; // 5 ms
+
+#[derive(Copy, Clone)]
+pub struct MidiEvent {
+    pub status: u8,
+    pub data1: u8,
+    pub data2: u8,
+}
+
+impl MidiEvent {
+    /// Note Off, or its running status friendly form, Note On with velocity 0
+    fn is_note_off(&self) -> bool {
+        match self.status & 0xF0 {
+            0x80 => true,
+            0x90 => self.data2 == 0,
+            _ => false,
+        }
+    }
+
+    /// Note Off, Note On with velocity 0, panic, All Sound Off or All Notes Off
+    fn releases_notes(&self) -> bool {
+        self.is_note_off() || (self.status & 0xF0 == 0xB0 && matches!(self.data1, 119 | 120 | 123))
+    }
+}
+
+/// Add an event to the MIDI queue. When the queue is nearly full only events that
+/// release notes get in, and anything dropped is counted in `MIDI_EVENTS_DROPPED`.
+pub fn enqueue_midi_event(
+    prod: &mut Producer<'static, MidiEvent, MIDI_QUEUE_SIZE>,
+    event: MidiEvent,
+) {
+    let reserved = prod.len() + NOTE_OFF_RESERVE >= prod.capacity();
+    if (reserved && !event.releases_notes()) || prod.enqueue(event).is_err() {
+        let dropped = MIDI_EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "MIDI queue full, dropped status={:#X} ({} dropped so far)",
+            event.status, dropped
+        );
+    }
+}
+
+/// Whether pitch bend affects the whole instrument or only the voices on the bend's
+/// MIDI channel (as used by MPE controllers)
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum BendScope {
+    Global,
+    PerNote,
+}
+
+/// The parameter that Data Entry (MIDI CC 6) sets, chosen by its number's MSB and LSB:
+/// CC 101 and 100 for a registered parameter (RPN), CC 99 and 98 for a non-registered
+/// one (NRPN). RPN 127/127 is the null parameter, which deselects.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ParamNumber {
+    None,
+    Rpn(u8, u8),
+    Nrpn(u8, u8),
+}
+
+/// Calibration signals for measuring the analog output stage (MIDI CC 34, or hold CC 117
+/// for the full scale sine). These bypass the voices, filter and mute entirely and write
+/// exact levels to both channels, once they've faded in:
+///
+/// - `FullScaleSine`: 1 kHz sine, peak +/-32767 (0 dBFS)
+/// - `Tone`: 1 kHz sine, peak +/-3277 (-20 dBFS)
+/// - `Dc`: constant +16384 (-6 dBFS)
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TestSignal {
+    Off,
+    FullScaleSine,
+    Tone,
+    Dc,
+}
+
+/// Which voice a note takes when every voice is in use (MIDI CC 89). `Quietest` takes a
+/// released voice if there is one, and `None` drops the new note instead.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum StealPolicy {
+    Oldest,
+    Quietest,
+    LowestNote,
+    None,
+}
+
+/// How portamento glides between pitches (MIDI CC 63). `Linear` changes the frequency by
+/// the same number of Hz every sample, so the pitch moves fastest at the start of a glide
+/// up. `Exponential` moves by the same number of semitones every sample, which sounds like
+/// a steady slide for any interval.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum GlideCurve {
+    Linear,
+    Exponential,
+}
+
+/// Velocity to amplitude mapping (MIDI CC 57). `Soft` (convex) gets louder quickly for
+/// light playing, `Hard` (concave) needs a heavier touch, and `Fixed` ignores velocity.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum VelocityCurve {
+    Linear,
+    Soft,
+    Hard,
+    Fixed,
+}
+
+/// How the envelope restarts when a note starts on a voice that's still sounding (MIDI
+/// CC 56):
+///
+/// - `Partial`: attack from the current level (the default)
+/// - `Full`: attack from zero
+/// - `Continue`: carry on from the current level without a new attack peak
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Retrigger {
+    Partial,
+    Full,
+    Continue,
+}
+
+/// Envelope segment shape (MIDI CC 38, while no RPN/NRPN is selected). In exponential
+/// mode the stage times are RC time constants: each stage covers 63% of its remaining
+/// distance per time constant, and ends once it is within `EXP_ENV_THRESHOLD` of its
+/// target.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum EnvCurve {
+    Linear,
+    Exponential,
+}
+
+/// Which state-variable filter output the voices use (MIDI CC 44). Notch is the sum of
+/// the low-pass and high-pass outputs.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum FilterType {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+/// Which state-variable filter the voices use (MIDI CC 88). The Chamberlain filter is
+/// the original, and cheap, but its coefficient has to be clamped to stay stable as
+/// the cutoff nears Nyquist. The TPT (topology-preserving transform) filter is stable
+/// right up to Nyquist, so it sweeps fully open cleanly.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum FilterModel {
+    Chamberlain,
+    Tpt,
+}
+
+/// Which sound parameters the voices use. With multitimbral mode off, every voice uses
+/// patch A. Otherwise patch-parameter CCs and Program Changes on MIDI channel 2 edit
+/// patch B, and on any other channel patch A, and notes are routed by `MultiMode`.
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum MultiMode {
+    Off,
+    Split,   // notes below the split point play patch A, the rest patch B
+    Layer,   // every note plays both patches
+    Channel, // MIDI channel 2 plays patch B, the rest patch A
+}
+
+const N_PATCHES: usize = 2;
+
+// Enum values in the order they're stored in a parameter block
+const WAVEFORMS: [Waveform; 8] = [
+    Waveform::Sine,
+    Waveform::Square,
+    Waveform::Sawtooth,
+    Waveform::Triangle,
+    Waveform::Noise,
+    Waveform::Wavetable,
+    Waveform::Morph,
+    Waveform::PluckedString,
+];
+const FILTER_TYPES: [FilterType; 4] = [
+    FilterType::LowPass,
+    FilterType::HighPass,
+    FilterType::BandPass,
+    FilterType::Notch,
+];
+const FILTER_MODELS: [FilterModel; 2] = [FilterModel::Chamberlain, FilterModel::Tpt];
+const MULTI_MODES: [MultiMode; 4] = [
+    MultiMode::Off,
+    MultiMode::Split,
+    MultiMode::Layer,
+    MultiMode::Channel,
+];
+const ENV_CURVES: [EnvCurve; 2] = [EnvCurve::Linear, EnvCurve::Exponential];
+const RETRIGGERS: [Retrigger; 3] = [Retrigger::Partial, Retrigger::Full, Retrigger::Continue];
+const VELOCITY_CURVES: [VelocityCurve; 4] = [
+    VelocityCurve::Linear,
+    VelocityCurve::Soft,
+    VelocityCurve::Hard,
+    VelocityCurve::Fixed,
+];
+const STEAL_POLICIES: [StealPolicy; 4] = [
+    StealPolicy::Oldest,
+    StealPolicy::Quietest,
+    StealPolicy::LowestNote,
+    StealPolicy::None,
+];
+// Linear first, so blocks saved before glide curves keep linear glide
+const GLIDE_CURVES: [GlideCurve; 2] = [GlideCurve::Linear, GlideCurve::Exponential];
+
+// Appends values to a parameter block, little endian
+struct ParamWriter {
+    buf: Params,
+    pos: usize,
+}
+
+impl ParamWriter {
+    fn u8(&mut self, value: u8) {
+        self.buf[self.pos] = value;
+        self.pos += 1;
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.buf[self.pos..self.pos + 4].copy_from_slice(&value.to_le_bytes());
+        self.pos += 4;
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    // An enum value, as its position in `values`
+    fn index<T: PartialEq>(&mut self, values: &[T], value: T) {
+        let i = values.iter().position(|v| *v == value).unwrap_or(0);
+        self.u8(i as u8);
+    }
+}
+
+// Reads values back in the order `ParamWriter` wrote them. Out of range values are
+// clamped, so a block from elsewhere (eg. a SysEx dump) can't break the synth.
+struct ParamReader<'a> {
+    buf: &'a Params,
+    pos: usize,
+}
+
+impl ParamReader<'_> {
+    fn u8(&mut self) -> u8 {
+        self.pos += 1;
+        self.buf[self.pos - 1]
+    }
+
+    fn f32(&mut self, min: f32, max: f32) -> f32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.buf[self.pos..self.pos + 4]);
+        self.pos += 4;
+        let value = f32::from_le_bytes(bytes);
+        if value.is_nan() {
+            min
+        } else {
+            value.clamp(min, max)
+        }
+    }
+
+    fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    fn index<T: Copy>(&mut self, values: &[T]) -> T {
+        let i = self.u8() as usize;
+        values.get(i).copied().unwrap_or(values[0])
+    }
+}
+
+/// The sound parameters that differ between the voice groups in multitimbral mode
+#[derive(Copy, Clone)]
+struct Patch {
+    // Waveform (controllable via MIDI CC 21), shared by both oscillators
+    waveform: Waveform,
+    // Square wave duty cycle (MIDI CC 49), 0.05 to 0.95
+    pulse_width: f32,
+    // Position through the wavetables (MIDI CC 52), 0.0 to 1.0
+    wavetable_pos: f32,
+    // Position of the morphing waveform (MIDI CC 61), 0.0 (sine) to 1.0 (square)
+    morph_pos: f32,
+    // ADSR parameters (controllable via MIDI CC 22-25)
+    attack_time_s: f32,
+    decay_time_s: f32,
+    sustain_level: f32,
+    release_time_s: f32,
+    // Filter parameters (controllable via MIDI CC 26-27)
+    filter_cutoff: f32, // 0.0 to 1.0 (FILTER_MIN_HZ to FILTER_MAX_HZ, exponentially)
+    filter_resonance: f32, // 0.0 to 4.0
+    filter_type: FilterType, // MIDI CC 44
+    // Cached state-variable filter coefficients for the above, for both filter models
+    filter_f: f32,
+    filter_q: f32,
+    tpt_g: f32,
+    tpt_k: f32,
+}
+
+impl Patch {
+    fn new() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            pulse_width: 0.5,
+            wavetable_pos: 0.0,
+            morph_pos: 0.0,
+            attack_time_s: DEFAULT_ATTACK_TIME_S,
+            decay_time_s: DEFAULT_DECAY_TIME_S,
+            sustain_level: DEFAULT_SUSTAIN_LEVEL,
+            release_time_s: DEFAULT_RELEASE_TIME_S,
+            filter_cutoff: DEFAULT_FILTER_CUTOFF,
+            filter_resonance: DEFAULT_FILTER_RESONANCE,
+            filter_type: FilterType::LowPass,
+            filter_f: svf_f(cutoff_hz(DEFAULT_FILTER_CUTOFF)),
+            filter_q: svf_q(DEFAULT_FILTER_RESONANCE),
+            tpt_g: tpt_g(cutoff_hz(DEFAULT_FILTER_CUTOFF)),
+            tpt_k: tpt_k(DEFAULT_FILTER_RESONANCE),
+        }
+    }
+
+    /// Recompute the cached filter coefficients after a cutoff/resonance change
+    fn update_filter_coefficients(&mut self) {
+        let hz = cutoff_hz(self.filter_cutoff);
+        self.filter_f = svf_f(hz);
+        self.filter_q = svf_q(self.filter_resonance);
+        self.tpt_g = tpt_g(hz);
+        self.tpt_k = tpt_k(self.filter_resonance);
+    }
+
+    /// An oscillator for this patch's waveform, with `seed` for any noise
+    fn osc(&self, seed: u32) -> Osc {
+        Osc::new(
+            self.waveform,
+            self.pulse_width,
+            self.wavetable_pos,
+            self.morph_pos,
+            seed,
+        )
+    }
+}
+
+/// A built-in sound, selected with Program Change. Applying one sets every parameter
+/// it holds together, between two buffers.
+struct Preset {
+    name: &'static str,
+    waveform: Waveform,
+    attack_time_s: f32,
+    decay_time_s: f32,
+    sustain_level: f32,
+    release_time_s: f32,
+    filter_cutoff: f32,
+    filter_resonance: f32,
+    osc_detune_cents: f32,
+    osc_mix: f32,
+}
+
+const PRESETS: [Preset; 8] = [
+    Preset {
+        name: "Lead",
+        waveform: Waveform::Sawtooth,
+        attack_time_s: 0.005,
+        decay_time_s: 0.200,
+        sustain_level: 0.7,
+        release_time_s: 0.200,
+        filter_cutoff: 0.81,
+        filter_resonance: 1.5,
+        osc_detune_cents: 10.0,
+        osc_mix: 0.5,
+    },
+    Preset {
+        name: "Pad",
+        waveform: Waveform::Sawtooth,
+        attack_time_s: 1.200,
+        decay_time_s: 1.000,
+        sustain_level: 0.8,
+        release_time_s: 2.000,
+        filter_cutoff: 0.7,
+        filter_resonance: 0.5,
+        osc_detune_cents: 25.0,
+        osc_mix: 0.5,
+    },
+    Preset {
+        name: "Bass",
+        waveform: Waveform::Square,
+        attack_time_s: 0.002,
+        decay_time_s: 0.300,
+        sustain_level: 0.4,
+        release_time_s: 0.100,
+        filter_cutoff: 0.57,
+        filter_resonance: 2.5,
+        osc_detune_cents: 0.0,
+        osc_mix: 0.0,
+    },
+    Preset {
+        name: "Organ",
+        waveform: Waveform::Sine,
+        attack_time_s: 0.005,
+        decay_time_s: 0.050,
+        sustain_level: 1.0,
+        release_time_s: 0.050,
+        filter_cutoff: 0.95,
+        filter_resonance: 0.0,
+        osc_detune_cents: 3.0,
+        osc_mix: 0.3,
+    },
+    Preset {
+        name: "Pluck",
+        waveform: Waveform::Triangle,
+        attack_time_s: 0.001,
+        decay_time_s: 0.400,
+        sustain_level: 0.0,
+        release_time_s: 0.300,
+        filter_cutoff: 0.76,
+        filter_resonance: 1.0,
+        osc_detune_cents: 0.0,
+        osc_mix: 0.0,
+    },
+    Preset {
+        name: "Strings",
+        waveform: Waveform::Sawtooth,
+        attack_time_s: 0.400,
+        decay_time_s: 0.500,
+        sustain_level: 0.9,
+        release_time_s: 0.800,
+        filter_cutoff: 0.79,
+        filter_resonance: 0.3,
+        osc_detune_cents: 15.0,
+        osc_mix: 0.5,
+    },
+    Preset {
+        name: "Brass",
+        waveform: Waveform::Sawtooth,
+        attack_time_s: 0.080,
+        decay_time_s: 0.300,
+        sustain_level: 0.6,
+        release_time_s: 0.150,
+        filter_cutoff: 0.74,
+        filter_resonance: 1.0,
+        osc_detune_cents: 6.0,
+        osc_mix: 0.4,
+    },
+    Preset {
+        name: "Wind",
+        waveform: Waveform::Noise,
+        attack_time_s: 0.500,
+        decay_time_s: 0.500,
+        sustain_level: 0.8,
+        release_time_s: 1.000,
+        filter_cutoff: 0.6,
+        filter_resonance: 3.5,
+        osc_detune_cents: 0.0,
+        osc_mix: 0.0,
+    },
+];
+
+/// How samples are encoded for the DAC. Most I2S DACs take signed (two's complement)
+/// samples, but some want offset binary, where silence is mid-scale rather than zero.
+/// The PWM output converts from signed samples, so it always uses `Signed`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "pwm-audio", allow(dead_code))]
+pub enum SampleFormat {
+    Signed,
+    OffsetBinary,
+}
+
+// Pack left and right 16-bit samples into a single u32, as that's what the I2S DMA
+// expects. Left goes in the high half, which is shifted out first.
+#[cfg(not(feature = "i2s-32bit"))]
+const SIGN_BITS: u32 = 0x8000_8000;
+#[cfg(feature = "i2s-32bit")]
+const SIGN_BITS: u32 = 0x8000_0000;
+
+#[inline]
+#[cfg(not(feature = "i2s-32bit"))]
+fn pack_lr_16(l: i16, r: i16) -> u32 {
+    ((l as u32 as u16 as u32) << 16) | ((r as u16) as u32)
+}
+
+// Write one stereo frame of output. Samples are scaled for 16-bit output (full scale
+// is +/-32767); with 32-bit output the fractional part is kept as extra resolution,
+// which 24-bit DACs pick up from the top 24 bits of each word. This is the one place
+// the channel order is decided, so the `swap-lr` feature corrects a DAC (or wiring)
+// that comes out reversed.
+#[inline]
+fn write_frame(frame: &mut [u32], l: f32, r: f32, format: SampleFormat) {
+    #[cfg(feature = "swap-lr")]
+    let (l, r) = (r, l);
+    #[cfg(not(feature = "i2s-32bit"))]
+    {
+        frame[0] = pack_lr_16(l as i16, r as i16);
+    }
+    #[cfg(feature = "i2s-32bit")]
+    {
+        frame[0] = (l * 65536.0) as i32 as u32;
+        frame[1] = (r * 65536.0) as i32 as u32;
+    }
+    // Offset binary is signed with the sign bit of each sample flipped, so 0.0 maps to
+    // mid-scale
+    if format == SampleFormat::OffsetBinary {
+        for word in frame.iter_mut() {
+            *word ^= SIGN_BITS;
+        }
+    }
+}
+
+// Filter cutoff in Hz, from the cutoff control (0.0 to 1.0)
+#[inline]
+fn cutoff_hz(cutoff: f32) -> f32 {
+    FILTER_MIN_HZ * (FILTER_MAX_HZ / FILTER_MIN_HZ).powf(cutoff)
+}
+
+// Chamberlain state-variable filter frequency coefficient, from the cutoff in Hz. It's
+// clamped, as the filter goes unstable towards Nyquist.
+#[inline]
+fn svf_f(hz: f32) -> f32 {
+    (2.0 * (core::f32::consts::PI * hz / SAMPLE_RATE as f32).sin()).min(1.5)
+}
+
+// Zero out values small enough to be heading for denormals, eg. filter state decaying
+// after a note ends, which would otherwise linger and be slow to process
+#[inline]
+fn flush_denormal(x: f32) -> f32 {
+    if x.abs() < DENORMAL_THRESHOLD { 0.0 } else { x }
+}
+
+// Chamberlain state-variable filter damping, from the resonance (0.0 to 4.0)
+#[inline]
+fn svf_q(resonance: f32) -> f32 {
+    let q = 1.0 - resonance * 0.24; // Damping (lower = more resonance)
+    q.max(0.05) // Prevent complete instability
+}
+
+// TPT state-variable filter gain, from the cutoff in Hz. The cutoff is prewarped, and
+// kept just below Nyquist, where tan() blows up.
+#[inline]
+fn tpt_g(hz: f32) -> f32 {
+    let fraction = (hz / SAMPLE_RATE as f32).min(TPT_MAX_CUTOFF);
+    (core::f32::consts::PI * fraction).tan()
+}
+
+// TPT state-variable filter damping (2.0 is no resonance), from the resonance (0.0 to
+// 4.0). It stays above zero, so the filter never self-oscillates.
+#[inline]
+fn tpt_k(resonance: f32) -> f32 {
+    2.0 - resonance * 0.49
+}
+
+// Pan position of a note, -1.0 left to 1.0 right: its channel's pan, offset by its
+// distance from middle C times the keyboard pan spread, so low notes sit to the left
+#[inline]
+fn note_pan(channel_pan: f32, spread: f32, note: u8) -> f32 {
+    (channel_pan + spread * (note as f32 - 60.0) / 64.0).clamp(-1.0, 1.0)
+}
+
+// Note amplitude for a note on velocity, according to the velocity curve
+#[inline]
+fn velocity_amp(curve: VelocityCurve, velocity: u8) -> f32 {
+    let v = velocity as f32 / 127.0;
+    match curve {
+        VelocityCurve::Linear => v,
+        VelocityCurve::Soft => v.sqrt(),
+        VelocityCurve::Hard => v * v,
+        VelocityCurve::Fixed => FIXED_VELOCITY_AMP,
+    }
+}
+
+// A 0.0 to 1.0 control value from a controller's MSB and LSB. The MSB alone maps just
+// as it does without the LSB, so controllers that only send MSBs aren't affected.
+#[cfg(feature = "hires-cc")]
+fn cc_14bit(msb: u8, lsb: u8) -> f32 {
+    ((msb as f32 + lsb as f32 / 128.0) / 127.0).min(1.0)
+}
+
+/// How MIDI notes map to frequencies: the pitch of A4 (note 69), and a 12-note scale of
+/// offsets from equal temperament, in cents, from C up to B. The scale repeats every
+/// octave, so it can hold eg. just intonation or a non-Western scale.
+#[derive(Copy, Clone)]
+pub struct Tuning {
+    pub a4_hz: f32,
+    pub cents: [f32; 12],
+}
+
+impl Tuning {
+    /// Standard tuning: A4 = 440 Hz, 12-tone equal temperament
+    pub const STANDARD: Self = Self {
+        a4_hz: 440.0,
+        cents: [0.0; 12],
+    };
+
+    #[inline]
+    fn note_to_freq(&self, note: u8) -> f32 {
+        let semitones = (note as i32 - 69) as f32 + self.cents[note as usize % 12] / 100.0;
+        self.a4_hz * 2f32.powf(semitones / 12.0)
+    }
+}
+
+/// The synth's starting sound, for `Synth::with_config`. Both patches start with it.
+/// Everything else starts at its default, and all of it can still be changed by MIDI.
+#[derive(Copy, Clone)]
+pub struct SynthConfig {
+    pub waveform: Waveform,
+    pub attack_time_s: f32,
+    pub decay_time_s: f32,
+    pub sustain_level: f32,
+    pub release_time_s: f32,
+    pub filter_cutoff: f32, // 0.0 to 1.0 (FILTER_MIN_HZ to FILTER_MAX_HZ, exponentially)
+    pub filter_resonance: f32, // 0.0 to 4.0
+    // Voices notes can be played on, 1 to N_VOICES. Fewer voices means less CPU load.
+    pub polyphony: usize,
+    pub tuning: Tuning,
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            attack_time_s: DEFAULT_ATTACK_TIME_S,
+            decay_time_s: DEFAULT_DECAY_TIME_S,
+            sustain_level: DEFAULT_SUSTAIN_LEVEL,
+            release_time_s: DEFAULT_RELEASE_TIME_S,
+            filter_cutoff: DEFAULT_FILTER_CUTOFF,
+            filter_resonance: DEFAULT_FILTER_RESONANCE,
+            polyphony: N_VOICES,
+            tuning: Tuning::STANDARD,
+        }
+    }
+}
+
+/// Minimal synth that owns a MIDI consumer and generates audio from it.
+pub struct Synth {
+    cons: heapless::spsc::Consumer<'static, MidiEvent, MIDI_QUEUE_SIZE>,
+    voices: [Voice; N_VOICES],
+    // Indices of the voices that may be sounding, so rendering skips the rest without
+    // touching them. Voices are added when they start a note, and pruned after each
+    // render once they've gone silent.
+    active: heapless::Vec<usize, N_VOICES>,
+    polyphony: usize, // notes are only played on the first `polyphony` voices
+    tuning: Tuning,
+    age_counter: u32,
+    // Mod wheel (MIDI CC 1), 0.0 to 1.0, scales the vibrato depth
+    mod_wheel: f32,
+    vibrato_phase: f32,
+    // LFO keytracking (MIDI CC 28), 0.0 to 1.0. The LFO is shared between voices, so it
+    // tracks the most recently played note. At 1.0 the rate doubles per octave.
+    lfo_keytrack: f32,
+    last_note: u8,
+    // Sound parameters per voice group. Only patch A is used unless multitimbral mode
+    // (MIDI CC 86) is on, with the split point set by MIDI CC 87.
+    patches: [Patch; N_PATCHES],
+    // Each patch's sustain level, smoothed. Held notes follow it, so sustain changes
+    // are heard straight away.
+    sustain_smoothed: [f32; N_PATCHES],
+    multi_mode: MultiMode,
+    split_note: u8,
+    // Unison: voices per note (MIDI CC 54) and their total detune spread (MIDI CC 55)
+    unison_count: usize,      // 1 to 4
+    unison_detune_cents: f32, // 0.0 to 50.0
+    // Start each note at a random oscillator phase (MIDI CC 53), rather than zero
+    random_phase: bool,
+    // Second oscillator: detune spread around the note (MIDI CC 35) and mix (MIDI CC 36)
+    osc_detune_cents: f32, // 0.0 to 50.0
+    osc_mix: f32,          // 0.0 (osc 1 only) to 1.0 (osc 2 only)
+    osc_sync: bool,        // hard sync osc 2 to osc 1 (MIDI CC 50)
+    ring_mod: bool,        // mix osc 1 * osc 2 in place of osc 2 (MIDI CC 51)
+    // White noise blended into the oscillators, pre-filter (MIDI CC 37)
+    noise_mix: f32,
+    // Square sub-oscillator an octave below osc 1, added pre-filter (MIDI CC 58)
+    sub_level: f32, // 0.0 to 1.0
+    // 2-operator FM: a sine modulator at a ratio of the note (MIDI CC 103) modulates osc
+    // 1's frequency by an index (MIDI CC 104, 0 is off), scaled by its own envelope,
+    // which decays from the note on (MIDI CC 105)
+    fm_ratio: f32, // 0.5 to 8.0
+    fm_index: f32, // 0.0 to 10.0
+    fm_decay_s: f32,
+    env_curve: EnvCurve, // MIDI CC 38
+    // AD envelope (MIDI CC 60): decay to zero even while the note is held, for plucks and
+    // drums, rather than holding at the sustain level
+    ad_mode: bool,
+    env_keytrack: f32, // 0.0 to 1.0, MIDI CC 59
+    // Velocity to attack time (MIDI CC 90), 0.0 to 1.0. Harder notes attack faster.
+    vel_to_attack: f32,
+    filter_model: FilterModel,     // MIDI CC 88
+    retrigger: Retrigger,          // MIDI CC 56
+    velocity_curve: VelocityCurve, // MIDI CC 57
+    steal_policy: StealPolicy,     // MIDI CC 89
+    // Per-voice cutoff modulation, summed with the patch's cutoff and clamped once. It's
+    // added before the cutoff is converted to Hz, so it moves the cutoff by an interval.
+    filter_env_amount: f32, // 0.0 to 1.0, follows the amp envelope (CC 29)
+    filter_keytrack: f32,   // 0.0 to 1.0, cutoff offset per semitone from middle C (CC 30)
+    vel_to_cutoff: f32,     // 0.0 to 1.0, cutoff offset at full velocity (CC 39)
+    channel_pressure: f32,  // 0.0 to 1.0, from channel pressure (0xD0)
+    // Mono/legato mode (MIDI CC 126 = mono on, CC 127 = poly on). In mono mode only
+    // voice 0 sounds, and a new note while another is held glides without retriggering.
+    mono: bool,
+    // Portamento: glide time (MIDI CC 5), used by mono legato too, and whether every new
+    // note glides from the previous note's pitch (MIDI CC 65)
+    portamento_time_s: f32,
+    portamento: bool,
+    glide_curve: GlideCurve, // MIDI CC 63
+    last_freq: Option<f32>,  // None until the first note
+    held_notes: heapless::Vec<u8, N_HELD_NOTES>,
+    // Latch (MIDI CC 62): notes played while it's on ignore their note offs, and are
+    // released by playing them again, or by turning the latch off
+    latch: bool,
+    latched: heapless::Vec<(u8, u8), N_LATCHED_NOTES>, // (note, channel)
+    // Master volume (MIDI CC 7), and mute (MIDI CC 31, >= 64 mutes). Voices keep
+    // running while muted. The output gain ramps to the volume, or to zero when muted.
+    volume: f32, // 0.0 to 1.0
+    // With the `hires-cc` feature, the last MSB of the volume and of each patch's filter
+    // cutoff, which the LSBs (MIDI CC 39 and 58) refine
+    #[cfg(feature = "hires-cc")]
+    volume_msb: u8,
+    #[cfg(feature = "hires-cc")]
+    cutoff_msb: [u8; N_PATCHES],
+    // Smoothed 1 / sqrt(sounding voices), scaling the mix
+    mix_norm: f32,
+    muted: bool,
+    master_gain: f32,
+    // Pitch bend frequency ratio per MIDI channel. In Global scope (the default) every
+    // entry is updated together. Scope is selected with MIDI CC 33 (>= 64 is PerNote).
+    bend_ratio: [f32; N_CHANNELS],
+    bend_scope: BendScope,
+    bend_range_semitones: f32, // RPN 0
+    // Global transpose of new notes (RPN 2) and fine tune of all of them (RPN 1), for
+    // playing along with fixed-pitch recordings
+    transpose_semitones: i8, // -64 to 63
+    fine_tune_cents: f32,    // -100.0 to 100.0
+    // Parameter selected for Data Entry by RPN/NRPN (MIDI CC 98-101), and the last Data
+    // Entry MSB (MIDI CC 6), which the LSB (MIDI CC 38) refines
+    param_number: ParamNumber,
+    data_entry_msb: u8,
+    // Pan position per MIDI channel (MIDI CC 10), -1.0 left to 1.0 right
+    channel_pan: [f32; N_CHANNELS],
+    // Spread of notes across the stereo field by pitch (MIDI CC 102), 0.0 (none) to 1.0
+    keyboard_pan_spread: f32,
+    // MIDI clock, which sets the arpeggiator tempo and LFO rate when it's received
+    clock: MidiClock,
+    sample_count: u32, // samples rendered, wrapping, for timing the clock
+    // Arpeggiator: on/off MIDI CC 46, mode MIDI CC 47, tempo MIDI CC 48
+    arp: Arpeggiator,
+    // Master effects
+    // - chorus: depth MIDI CC 40, rate MIDI CC 41, mix MIDI CC 45
+    // - delay: time MIDI CC 85, feedback MIDI CC 42, mix MIDI CC 43
+    effects: EffectChain,
+    #[cfg(feature = "metering")]
+    meter: Meter,
+    // Calibration signal generator, a quadrature oscillator (cos, sin) so the sine
+    // stays clean without relying on the approximate `sin()`
+    test_signal: TestSignal, // the signal playing, which fades out before switching
+    test_target: TestSignal, // the signal selected
+    test_gain: f32,
+    test_osc: (f32, f32),
+    sample_format: SampleFormat,
+    // Voices fading out after a preset change (the `preset-crossfade` feature)
+    #[cfg(feature = "preset-crossfade")]
+    crossfade: Crossfade,
+}
+
+impl Synth {
+    pub fn new(cons: heapless::spsc::Consumer<'static, MidiEvent, MIDI_QUEUE_SIZE>) -> Self {
+        Self::with_config(cons, SynthConfig::default())
+    }
+
+    /// A synth with `config` as its starting sound
+    pub fn with_config(
+        cons: heapless::spsc::Consumer<'static, MidiEvent, MIDI_QUEUE_SIZE>,
+        config: SynthConfig,
+    ) -> Self {
+        let mut patch = Patch::new();
+        patch.waveform = config.waveform;
+        patch.attack_time_s = config.attack_time_s.clamp(0.001, 2.0);
+        patch.decay_time_s = config.decay_time_s.clamp(0.001, 2.0);
+        patch.sustain_level = config.sustain_level.clamp(0.0, 1.0);
+        patch.release_time_s = config.release_time_s.clamp(0.001, 3.0);
+        patch.filter_cutoff = config.filter_cutoff.clamp(0.0, 1.0);
+        patch.filter_resonance = config.filter_resonance.clamp(0.0, 4.0);
+        patch.update_filter_coefficients();
+        Self {
+            cons,
+            voices: [Voice::new(); N_VOICES],
+            active: heapless::Vec::new(),
+            polyphony: config.polyphony.clamp(1, N_VOICES),
+            tuning: config.tuning,
+            age_counter: 0,
+            mod_wheel: 0.0,
+            vibrato_phase: 0.0,
+            lfo_keytrack: 0.0,
+            last_note: LFO_KEYTRACK_REF_NOTE,
+            patches: [patch; N_PATCHES],
+            sustain_smoothed: [patch.sustain_level; N_PATCHES],
+            multi_mode: MultiMode::Off,
+            split_note: 60,
+            unison_count: 1,
+            unison_detune_cents: 0.0,
+            random_phase: true,
+            osc_detune_cents: 0.0,
+            osc_mix: 0.0,
+            osc_sync: false,
+            ring_mod: false,
+            noise_mix: 0.0,
+            sub_level: 0.0,
+            fm_ratio: 1.0,
+            fm_index: 0.0,
+            fm_decay_s: FM_DEFAULT_DECAY_S,
+            env_keytrack: 0.0,
+            vel_to_attack: 0.0,
+            filter_model: FilterModel::Chamberlain,
+            env_curve: EnvCurve::Linear,
+            ad_mode: false,
+            retrigger: Retrigger::Partial,
+            velocity_curve: VelocityCurve::Linear,
+            steal_policy: StealPolicy::Oldest,
+            filter_env_amount: 0.0,
+            filter_keytrack: 0.0,
+            vel_to_cutoff: 0.0,
+            channel_pressure: 0.0,
+            mono: false,
+            portamento_time_s: 0.0,
+            portamento: false,
+            glide_curve: GlideCurve::Exponential,
+            last_freq: None,
+            held_notes: heapless::Vec::new(),
+            latch: false,
+            latched: heapless::Vec::new(),
+            volume: 1.0,
+            #[cfg(feature = "hires-cc")]
+            volume_msb: 127,
+            #[cfg(feature = "hires-cc")]
+            cutoff_msb: [0; N_PATCHES],
+            mix_norm: 1.0,
+            muted: false,
+            master_gain: 1.0,
+            bend_ratio: [1.0; N_CHANNELS],
+            bend_scope: BendScope::Global,
+            bend_range_semitones: PITCH_BEND_RANGE_SEMITONES,
+            transpose_semitones: 0,
+            fine_tune_cents: 0.0,
+            param_number: ParamNumber::None,
+            data_entry_msb: 0,
+            channel_pan: [0.0; N_CHANNELS],
+            keyboard_pan_spread: 0.0,
+            clock: MidiClock::new(),
+            sample_count: 0,
+            arp: Arpeggiator::new(),
+            effects: EffectChain::new(),
+            #[cfg(feature = "metering")]
+            meter: Meter::new(),
+            test_signal: TestSignal::Off,
+            test_target: TestSignal::Off,
+            test_gain: 0.0,
+            sample_format: SampleFormat::Signed,
+            test_osc: (1.0, 0.0),
+            #[cfg(feature = "preset-crossfade")]
+            crossfade: Crossfade::new(),
+        }
+    }
+    pub fn process(&mut self, buf: &mut [u32]) -> ControlFlow<(), ()> {
+        // SysEx parameter dumps, in and out
+        if let Some(params) = LOAD_PARAMS.try_take()
+            && self.load_params(&params)
+        {
+            info!("Loaded parameters from SysEx");
+        }
+        if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+            DUMP_PARAMS.signal(self.save_params());
+        }
+
+        // Drain MIDI events and update voice allocation, up to a limit per buffer
+        for _ in 0..MAX_EVENTS_PER_BUFFER {
+            let Some(event) = self.cons.dequeue() else {
+                break;
+            };
+            self.handle_event(&event);
+        }
+
+        // Switch test signal once the old one has faded out
+        if self.test_signal != self.test_target && self.test_gain == 0.0 {
+            self.test_signal = self.test_target;
+            self.test_osc = (1.0, 0.0);
+        }
+        if self.test_signal != TestSignal::Off {
+            self.render_test_signal(buf);
+            return ControlFlow::Continue(());
+        }
+
+        // Render up to each arpeggiator step, then play the step
+        let n_frames = buf.len() / WORDS_PER_FRAME;
+        let mut start = 0;
+        while start < n_frames {
+            let mut end = n_frames;
+            if !self.clock.stopped
+                && let Some(until_step) = self.arp.samples_until_step()
+            {
+                if until_step == 0 {
+                    self.arp_step();
+                    continue;
+                }
+                end = end.min(start + until_step as usize);
+            }
+            self.render(&mut buf[start * WORDS_PER_FRAME..end * WORDS_PER_FRAME]);
+            self.arp.advance((end - start) as u32);
+            start = end;
+        }
+        VOICES_SOUNDING.store(!self.active.is_empty(), Ordering::Relaxed);
+
+        ControlFlow::Continue(())
+    }
+
+    /// Apply one MIDI event. Normally events come from the MIDI queue, drained at the
+    /// start of `process`.
+    pub fn handle_event(&mut self, event: &MidiEvent) {
+        debug!(
+            "SYNTH: MIDI event: status={}, data1={}, data2={}",
+            event.status, event.data1, event.data2
+        );
+        let status_nybble = event.status & 0xF0;
+        match status_nybble {
+            0xB0 => {
+                // Control Change
+                let cc_num = event.data1;
+                let cc_val = event.data2;
+                let patch = self.channel_group(event.status & 0x0F);
+                match cc_num {
+                    1 => {
+                        // Mod wheel: map 0-127 to 0.0-1.0 vibrato depth
+                        self.mod_wheel = cc_val as f32 / 127.0;
+                        debug!("Mod wheel set to {}", self.mod_wheel);
+                    }
+                    5 => {
+                        // Portamento time: map 0-127 to 0.0-2.0 seconds
+                        self.portamento_time_s = (cc_val as f32 / 127.0) * 2.0;
+                        debug!("Portamento time set to {} s", self.portamento_time_s);
+                    }
+                    7 => {
+                        // Volume: map 0-127 to 0.0-1.0
+                        #[cfg(feature = "hires-cc")]
+                        {
+                            self.volume_msb = cc_val;
+                        }
+                        self.set_volume(cc_val as f32 / 127.0);
+                    }
+                    10 => {
+                        // Pan: map 0-127 to -1.0-1.0, with 64 centred
+                        let channel = event.status & 0x0F;
+                        let pan = ((cc_val as f32 - 64.0) / 63.0).max(-1.0);
+                        self.channel_pan[channel as usize] = pan;
+                        self.update_pans();
+                        debug!("Pan set to {}", pan);
+                    }
+                    21 => {
+                        // Waveform: divide 0-127 into 8 regions
+                        self.patches[patch].waveform = match cc_val {
+                            0..=15 => Waveform::Sine,
+                            16..=31 => Waveform::Square,
+                            32..=47 => Waveform::Sawtooth,
+                            48..=63 => Waveform::Triangle,
+                            64..=79 => Waveform::Noise,
+                            80..=95 => Waveform::Wavetable,
+                            96..=111 => Waveform::Morph,
+                            112..=127 => Waveform::PluckedString,
+                            _ => Waveform::Sine, // fallback
+                        };
+                        let waveform_name = match self.patches[patch].waveform {
+                            Waveform::Sine => "Sine",
+                            Waveform::Square => "Square",
+                            Waveform::Sawtooth => "Sawtooth",
+                            Waveform::Triangle => "Triangle",
+                            Waveform::Noise => "Noise",
+                            Waveform::Wavetable => "Wavetable",
+                            Waveform::Morph => "Morph",
+                            Waveform::PluckedString => "Plucked string",
+                        };
+                        self.update_oscillators(patch);
+                        debug!("Waveform set to {}", waveform_name);
+                    }
+                    22 => {
+                        // Attack time: map 0-127 to 0.001-2.0 seconds
+                        self.patches[patch].attack_time_s = 0.001 + (cc_val as f32 / 127.0) * 1.999;
+                        self.update_envelope_times(patch);
+                        debug!("Attack time set to {} s", self.patches[patch].attack_time_s);
+                    }
+                    23 => {
+                        // Decay time: map 0-127 to 0.001-2.0 seconds
+                        self.patches[patch].decay_time_s = 0.001 + (cc_val as f32 / 127.0) * 1.999;
+                        self.update_envelope_times(patch);
+                        debug!("Decay time set to {} s", self.patches[patch].decay_time_s);
+                    }
+                    24 => {
+                        // Sustain level: map 0-127 to 0.0-1.0
+                        self.patches[patch].sustain_level = cc_val as f32 / 127.0;
+                        debug!("Sustain level set to {}", self.patches[patch].sustain_level);
+                    }
+                    25 => {
+                        // Release time: map 0-127 to 0.001-3.0 seconds
+                        self.patches[patch].release_time_s =
+                            0.001 + (cc_val as f32 / 127.0) * 2.999;
+                        self.update_envelope_times(patch);
+                        debug!(
+                            "Release time set to {} s",
+                            self.patches[patch].release_time_s
+                        );
+                    }
+                    26 => {
+                        // Filter cutoff: map 0-127 to 0.0-1.0
+                        #[cfg(feature = "hires-cc")]
+                        {
+                            self.cutoff_msb[patch] = cc_val;
+                        }
+                        self.set_filter_cutoff(patch, cc_val as f32 / 127.0);
+                    }
+                    27 => {
+                        // Filter resonance: map 0-127 to 0.0-4.0
+                        self.patches[patch].filter_resonance = (cc_val as f32 / 127.0) * 4.0;
+                        self.patches[patch].update_filter_coefficients();
+                        debug!(
+                            "Filter resonance set to {}",
+                            self.patches[patch].filter_resonance
+                        );
+                    }
+                    28 => {
+                        // LFO keytrack: map 0-127 to 0.0-1.0
+                        self.lfo_keytrack = cc_val as f32 / 127.0;
+                        debug!("LFO keytrack set to {}", self.lfo_keytrack);
+                    }
+                    29 => {
+                        // Filter envelope amount: map 0-127 to 0.0-1.0
+                        self.filter_env_amount = cc_val as f32 / 127.0;
+                        debug!("Filter env amount set to {}", self.filter_env_amount);
+                    }
+                    30 => {
+                        // Filter keytrack: map 0-127 to 0.0-1.0
+                        self.filter_keytrack = cc_val as f32 / 127.0;
+                        debug!("Filter keytrack set to {}", self.filter_keytrack);
+                    }
+                    31 => {
+                        // Master mute: off below 64, on from 64
+                        self.muted = cc_val >= 64;
+                        debug!("Muted set to {}", self.muted);
+                    }
+                    33 => {
+                        // Pitch bend scope: Global below 64, PerNote from 64
+                        let scope = if cc_val >= 64 {
+                            BendScope::PerNote
+                        } else {
+                            BendScope::Global
+                        };
+                        if scope != self.bend_scope {
+                            self.bend_scope = scope;
+                            self.bend_ratio = [1.0; N_CHANNELS];
+                        }
+                        debug!("Pitch bend per-note set to {}", cc_val >= 64);
+                    }
+                    34 => {
+                        // Test signal: divide 0-127 into 4 regions
+                        self.test_target = match cc_val {
+                            0..=31 => TestSignal::Off,
+                            32..=63 => TestSignal::FullScaleSine,
+                            64..=95 => TestSignal::Tone,
+                            _ => TestSignal::Dc,
+                        };
+                        debug!("Test signal region set to {}", cc_val / 32);
+                    }
+                    35 => {
+                        // Osc detune: map 0-127 to 0-50 cents
+                        self.osc_detune_cents = (cc_val as f32 / 127.0) * 50.0;
+                        debug!("Osc detune set to {} cents", self.osc_detune_cents);
+                    }
+                    36 => {
+                        // Osc mix: map 0-127 to 0.0-1.0
+                        self.osc_mix = cc_val as f32 / 127.0;
+                        debug!("Osc mix set to {}", self.osc_mix);
+                    }
+                    37 => {
+                        // Noise mix: map 0-127 to 0.0-1.0
+                        self.noise_mix = cc_val as f32 / 127.0;
+                        debug!("Noise mix set to {}", self.noise_mix);
+                    }
+                    #[cfg(feature = "hires-cc")]
+                    58 => {
+                        // Filter cutoff LSB: refine the last cutoff MSB to 14 bits
+                        self.set_filter_cutoff(patch, cc_14bit(self.cutoff_msb[patch], cc_val));
+                    }
+                    #[cfg(not(feature = "hires-cc"))]
+                    58 => {
+                        // Sub-oscillator level: map 0-127 to 0.0-1.0
+                        self.sub_level = cc_val as f32 / 127.0;
+                        debug!("Sub osc level set to {}", self.sub_level);
+                    }
+                    59 => {
+                        // Envelope keytrack: map 0-127 to 0.0-1.0
+                        self.env_keytrack = cc_val as f32 / 127.0;
+                        self.update_env_scaling();
+                        debug!("Envelope keytrack set to {}", self.env_keytrack);
+                    }
+                    90 => {
+                        // Velocity to attack time: map 0-127 to 0.0-1.0
+                        self.vel_to_attack = cc_val as f32 / 127.0;
+                        self.update_env_scaling();
+                        debug!("Velocity to attack set to {}", self.vel_to_attack);
+                    }
+                    60 => {
+                        // AD envelope: off below 64, on from 64
+                        self.ad_mode = cc_val >= 64;
+                        debug!("AD envelope set to {}", self.ad_mode);
+                    }
+                    38 if self.param_number != ParamNumber::None => {
+                        // Data Entry LSB, refining the last MSB while a parameter is
+                        // selected by RPN/NRPN
+                        self.data_entry(self.data_entry_msb, cc_val);
+                    }
+                    38 => {
+                        // Envelope curve: linear below 64, exponential from 64. With
+                        // an RPN/NRPN selected this CC is the Data Entry LSB instead.
+                        self.env_curve = if cc_val >= 64 {
+                            EnvCurve::Exponential
+                        } else {
+                            EnvCurve::Linear
+                        };
+                        debug!("Exponential envelope set to {}", cc_val >= 64);
+                    }
+                    #[cfg(feature = "hires-cc")]
+                    39 => {
+                        // Volume LSB: refine the last volume MSB to 14 bits
+                        self.set_volume(cc_14bit(self.volume_msb, cc_val));
+                    }
+                    #[cfg(not(feature = "hires-cc"))]
+                    39 => {
+                        // Velocity to cutoff: map 0-127 to 0.0-1.0
+                        self.vel_to_cutoff = cc_val as f32 / 127.0;
+                        debug!("Velocity to cutoff set to {}", self.vel_to_cutoff);
+                    }
+                    40 => {
+                        // Chorus depth: map 0-127 to 0.0-1.0
+                        self.effects.chorus.depth = cc_val as f32 / 127.0;
+                        debug!("Chorus depth set to {}", self.effects.chorus.depth);
+                    }
+                    41 => {
+                        // Chorus rate: map 0-127 to 0.05-5.0 Hz
+                        self.effects.chorus.rate_hz = 0.05 + (cc_val as f32 / 127.0) * 4.95;
+                        debug!("Chorus rate set to {} Hz", self.effects.chorus.rate_hz);
+                    }
+                    42 => {
+                        // Delay feedback: map 0-127 to 0.0-0.95
+                        self.effects.delay.feedback = (cc_val as f32 / 127.0) * 0.95;
+                        debug!("Delay feedback set to {}", self.effects.delay.feedback);
+                    }
+                    43 => {
+                        // Delay mix: map 0-127 to 0.0-1.0
+                        self.effects.delay.mix = cc_val as f32 / 127.0;
+                        debug!("Delay mix set to {}", self.effects.delay.mix);
+                    }
+                    44 => {
+                        // Filter type: divide 0-127 into 4 regions
+                        self.patches[patch].filter_type = match cc_val {
+                            0..=31 => FilterType::LowPass,
+                            32..=63 => FilterType::HighPass,
+                            64..=95 => FilterType::BandPass,
+                            _ => FilterType::Notch,
+                        };
+                        let filter_type_name = match self.patches[patch].filter_type {
+                            FilterType::LowPass => "Low-pass",
+                            FilterType::HighPass => "High-pass",
+                            FilterType::BandPass => "Band-pass",
+                            FilterType::Notch => "Notch",
+                        };
+                        debug!("Filter type set to {}", filter_type_name);
+                    }
+                    45 => {
+                        // Chorus mix: map 0-127 to 0.0-1.0
+                        self.effects.chorus.mix = cc_val as f32 / 127.0;
+                        debug!("Chorus mix set to {}", self.effects.chorus.mix);
+                    }
+                    46 => {
+                        // Arpeggiator: off below 64, on from 64
+                        let enabled = cc_val >= 64;
+                        if enabled != self.arp.enabled {
+                            if let Some(n) = self.arp.clear() {
+                                self.release_note(n.note, n.channel);
+                            }
+                            self.arp.enabled = enabled;
+                        }
+                        debug!("Arpeggiator set to {}", enabled);
+                    }
+                    47 => {
+                        // Arpeggiator mode: divide 0-127 into 4 regions
+                        self.arp.mode = match cc_val {
+                            0..=31 => ArpMode::Up,
+                            32..=63 => ArpMode::Down,
+                            64..=95 => ArpMode::UpDown,
+                            _ => ArpMode::Random,
+                        };
+                        debug!("Arpeggiator mode region set to {}", cc_val / 32);
+                    }
+                    48 => {
+                        // Arpeggiator tempo: map 0-127 to 40-240 BPM
+                        self.arp.set_tempo(40.0 + (cc_val as f32 / 127.0) * 200.0);
+                        debug!("Arpeggiator tempo set to {} BPM", self.arp.tempo_bpm());
+                    }
+                    49 => {
+                        // Pulse width: map 0-127 to 0.05-0.95
+                        self.patches[patch].pulse_width = 0.05 + (cc_val as f32 / 127.0) * 0.9;
+                        self.update_oscillators(patch);
+                        debug!("Pulse width set to {}", self.patches[patch].pulse_width);
+                    }
+                    50 => {
+                        // Osc hard sync: off below 64, on from 64
+                        self.osc_sync = cc_val >= 64;
+                        debug!("Osc sync set to {}", self.osc_sync);
+                    }
+                    51 => {
+                        // Ring mod: off below 64, on from 64
+                        self.ring_mod = cc_val >= 64;
+                        debug!("Ring mod set to {}", self.ring_mod);
+                    }
+                    52 => {
+                        // Wavetable position: map 0-127 to 0.0-1.0
+                        self.patches[patch].wavetable_pos = cc_val as f32 / 127.0;
+                        self.update_oscillators(patch);
+                        debug!(
+                            "Wavetable position set to {}",
+                            self.patches[patch].wavetable_pos
+                        );
+                    }
+                    61 => {
+                        // Morph position: map 0-127 to 0.0-1.0
+                        self.patches[patch].morph_pos = cc_val as f32 / 127.0;
+                        self.update_oscillators(patch);
+                        debug!("Morph position set to {}", self.patches[patch].morph_pos);
+                    }
+                    53 => {
+                        // Random start phase: off below 64, on from 64
+                        self.random_phase = cc_val >= 64;
+                        debug!("Random phase set to {}", self.random_phase);
+                    }
+                    54 => {
+                        // Unison voices: divide 0-127 into 4 regions, 1-4 voices
+                        self.unison_count = 1 + cc_val as usize / 32;
+                        debug!("Unison count set to {}", self.unison_count);
+                    }
+                    55 => {
+                        // Unison detune: map 0-127 to 0-50 cents
+                        self.unison_detune_cents = (cc_val as f32 / 127.0) * 50.0;
+                        debug!("Unison detune set to {} cents", self.unison_detune_cents);
+                    }
+                    56 => {
+                        // Envelope retrigger: divide 0-127 into 3 regions
+                        self.retrigger = match cc_val {
+                            0..=42 => Retrigger::Partial,
+                            43..=84 => Retrigger::Full,
+                            _ => Retrigger::Continue,
+                        };
+                        debug!("Retrigger mode region set to {}", cc_val / 43);
+                    }
+                    57 => {
+                        // Velocity curve: divide 0-127 into 4 regions
+                        self.velocity_curve = match cc_val {
+                            0..=31 => VelocityCurve::Linear,
+                            32..=63 => VelocityCurve::Soft,
+                            64..=95 => VelocityCurve::Hard,
+                            _ => VelocityCurve::Fixed,
+                        };
+                        debug!("Velocity curve region set to {}", cc_val / 32);
+                    }
+                    89 => {
+                        // Voice stealing policy: divide 0-127 into 4 regions
+                        self.steal_policy = match cc_val {
+                            0..=31 => StealPolicy::Oldest,
+                            32..=63 => StealPolicy::Quietest,
+                            64..=95 => StealPolicy::LowestNote,
+                            _ => StealPolicy::None,
+                        };
+                        debug!("Voice stealing policy region set to {}", cc_val / 32);
+                    }
+                    62 => {
+                        // Latch: off below 64, on from 64. Turning it off lets go of
+                        // the latched notes.
+                        self.latch = cc_val >= 64;
+                        if !self.latch {
+                            while let Some((note, channel)) = self.latched.pop() {
+                                self.handle_note_off(note, channel);
+                            }
+                        }
+                        debug!("Latch set to {}", self.latch);
+                    }
+                    63 => {
+                        // Glide curve: linear below 64, exponential from 64
+                        self.glide_curve = if cc_val >= 64 {
+                            GlideCurve::Exponential
+                        } else {
+                            GlideCurve::Linear
+                        };
+                        debug!("Exponential glide set to {}", cc_val >= 64);
+                    }
+                    65 => {
+                        // Portamento: off below 64, on from 64
+                        self.portamento = cc_val >= 64;
+                        debug!("Portamento set to {}", self.portamento);
+                    }
+                    102 => {
+                        // Keyboard pan spread: map 0-127 to 0.0-1.0. At 1.0 the lowest
+                        // and highest notes are panned fully left and right.
+                        self.keyboard_pan_spread = cc_val as f32 / 127.0;
+                        self.update_pans();
+                        debug!("Keyboard pan spread set to {}", self.keyboard_pan_spread);
+                    }
+                    103 => {
+                        // FM ratio: 16 steps of 0.5, from 0.5 to 8.0
+                        self.fm_ratio = ((cc_val / 8) as f32 + 1.0) * 0.5;
+                        debug!("FM ratio set to {}", self.fm_ratio);
+                    }
+                    104 => {
+                        // FM index: map 0-127 to 0.0-10.0
+                        self.fm_index = (cc_val as f32 / 127.0) * FM_MAX_INDEX;
+                        debug!("FM index set to {}", self.fm_index);
+                    }
+                    105 => {
+                        // FM envelope decay time: map 0-127 to 0.001-4.0 seconds
+                        self.fm_decay_s = 0.001 + (cc_val as f32 / 127.0) * 3.999;
+                        debug!("FM decay time set to {} s", self.fm_decay_s);
+                    }
+                    85 => {
+                        // Delay time: map 0-127 to 1 sample - DELAY_MAX_SAMPLES
+                        self.effects.delay.delay_samples =
+                            1 + cc_val as usize * (DELAY_MAX_SAMPLES - 1) / 127;
+                        debug!(
+                            "Delay time set to {} samples",
+                            self.effects.delay.delay_samples
+                        );
+                    }
+                    86 => {
+                        // Multitimbral mode: divide 0-127 into 4 regions
+                        self.multi_mode = match cc_val {
+                            0..=31 => MultiMode::Off,
+                            32..=63 => MultiMode::Split,
+                            64..=95 => MultiMode::Layer,
+                            _ => MultiMode::Channel,
+                        };
+                        debug!("Multitimbral mode region set to {}", cc_val / 32);
+                    }
+                    87 => {
+                        // Split point: the lowest note that plays patch B
+                        self.split_note = cc_val;
+                        debug!("Split note set to {}", self.split_note);
+                    }
+                    88 => {
+                        // Filter model: Chamberlain below 64, TPT from 64
+                        self.filter_model = if cc_val >= 64 {
+                            FilterModel::Tpt
+                        } else {
+                            FilterModel::Chamberlain
+                        };
+                        debug!(
+                            "TPT filter set to {}",
+                            self.filter_model == FilterModel::Tpt
+                        );
+                    }
+                    6 => {
+                        // Data Entry MSB, for the parameter chosen by RPN/NRPN. It
+                        // clears the LSB, so a controller that only sends the MSB
+                        // gets whole steps.
+                        self.data_entry_msb = cc_val;
+                        self.data_entry(cc_val, 0);
+                    }
+                    98..=101 => {
+                        // NRPN LSB/MSB (98/99), RPN LSB/MSB (100/101). Each byte keeps
+                        // the other while the same kind of parameter is selected.
+                        let (msb, lsb) = match self.param_number {
+                            ParamNumber::Rpn(msb, lsb) if cc_num >= 100 => (msb, lsb),
+                            ParamNumber::Nrpn(msb, lsb) if cc_num < 100 => (msb, lsb),
+                            _ => (127, 127),
+                        };
+                        self.param_number = match cc_num {
+                            98 => ParamNumber::Nrpn(msb, cc_val),
+                            99 => ParamNumber::Nrpn(cc_val, lsb),
+                            100 => ParamNumber::Rpn(msb, cc_val),
+                            _ => ParamNumber::Rpn(cc_val, lsb),
+                        };
+                        if self.param_number == ParamNumber::Rpn(127, 127) {
+                            self.param_number = ParamNumber::None;
+                        }
+                    }
+                    106 if cc_val >= 64 => {
+                        // Save the sound to flash, on a value of 64 or more
+                        SAVE_PARAMS.signal(self.save_params());
+                        info!("Saving parameters to flash");
+                    }
+                    117 => {
+                        // Test tone: a full scale 1 kHz sine while held at 64 or more
+                        self.test_target = if cc_val >= 64 {
+                            TestSignal::FullScaleSine
+                        } else {
+                            TestSignal::Off
+                        };
+                        debug!("Test tone set to {}", cc_val >= 64);
+                    }
+                    118 => {
+                        // Log the synth's state, for debugging patches
+                        self.debug_dump();
+                    }
+                    119 => {
+                        // Panic: like All Sound Off, but also clears the filter states
+                        // and effect tails, to kill runaway resonance or feedback
+                        self.held_notes.clear();
+                        self.latched.clear();
+                        self.arp.clear();
+                        for v in self.voices.iter_mut() {
+                            v.pending = None;
+                            v.env = 0.0;
+                            v.gate = false;
+                            v.stage = EnvStage::Idle;
+                            v.stage_samples = 0;
+                            v.filter_buf0 = 0.0;
+                            v.filter_buf1 = 0.0;
+                        }
+                        #[cfg(feature = "preset-crossfade")]
+                        {
+                            self.crossfade.fade = None;
+                        }
+                        self.effects.reset();
+                        warn!("Panic, everything silenced");
+                    }
+                    120 => {
+                        // All Sound Off: silence every voice immediately
+                        self.held_notes.clear();
+                        self.latched.clear();
+                        self.arp.clear();
+                        for v in self.voices.iter_mut() {
+                            v.pending = None;
+                            v.env = 0.0;
+                            v.gate = false;
+                            v.stage = EnvStage::Idle;
+                            v.stage_samples = 0;
+                        }
+                        #[cfg(feature = "preset-crossfade")]
+                        {
+                            self.crossfade.fade = None;
+                        }
+                        debug!("All sound off");
+                    }
+                    121 => {
+                        // Reset All Controllers
+                        self.reset_controllers();
+                        debug!("Controllers reset");
+                    }
+                    123 => {
+                        // All Notes Off: release every held voice
+                        self.held_notes.clear();
+                        self.latched.clear();
+                        self.arp.clear();
+                        for v in self.voices.iter_mut() {
+                            v.pending = None;
+                            if v.gate {
+                                v.note_off(self.patches[v.group].release_time_s);
+                            }
+                        }
+                        debug!("All notes off");
+                    }
+                    126 | 127 => {
+                        // Mono mode on / poly mode on
+                        let mono = cc_num == 126;
+                        if mono != self.mono {
+                            self.mono = mono;
+                            self.held_notes.clear();
+                            self.latched.clear();
+                            for v in self.voices.iter_mut() {
+                                if v.gate {
+                                    v.note_off(self.patches[v.group].release_time_s);
+                                }
+                            }
+                            debug!("Mono mode set to {}", self.mono);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            0x80 | 0x90 if event.is_note_off() => {
+                // Note Off, or Note On with velocity 0
+                self.handle_note_off(event.data1, event.status & 0x0F);
+            }
+            0x90 => {
+                // Note On
+                let note = event.data1;
+                let channel = event.status & 0x0F;
+                let vel_amp = velocity_amp(self.velocity_curve, event.data2);
+                if self.latch && !self.latch_note(note, channel) {
+                    // the note was latched, and playing it again has let it go
+                } else if self.arp.enabled {
+                    self.arp.note_on(ArpNote {
+                        note,
+                        channel,
+                        vel_amp,
+                    });
+                } else {
+                    self.play_note(note, channel, vel_amp);
+                }
+            }
+            0xC0 => {
+                // Program Change: select a built-in preset
+                let program = event.data1 as usize;
+                match PRESETS.get(program) {
+                    Some(preset) => {
+                        let patch = self.channel_group(event.status & 0x0F);
+                        #[cfg(feature = "preset-crossfade")]
+                        self.start_crossfade();
+                        self.apply_preset(patch, preset);
+                        debug!("Program {} selected: {}", program, preset.name);
+                    }
+                    None => debug!("No preset for program {}", program),
+                }
+            }
+            0xF0 => match event.status {
+                0xF8 => {
+                    // Timing Clock
+                    if let Some(bpm) = self.clock.tick(self.sample_count) {
+                        self.arp.set_tempo(bpm);
+                        debug!("MIDI clock tempo {} BPM", bpm);
+                    }
+                }
+                0xFA => {
+                    // Start: restart the LFO and arpeggiator in time with the host
+                    self.clock.start();
+                    self.vibrato_phase = 0.0;
+                    self.arp.restart();
+                    debug!("MIDI clock start");
+                }
+                0xFB => {
+                    // Continue
+                    self.clock.stopped = false;
+                    debug!("MIDI clock continue");
+                }
+                0xFC => {
+                    // Stop: freeze the LFO and arpeggiator
+                    self.clock.stopped = true;
+                    debug!("MIDI clock stop");
+                }
+                _ => {}
+            },
+            0xE0 => {
+                // Pitch Bend: 14-bit value centred on 8192
+                let channel = event.status & 0x0F;
+                let value = ((event.data2 as i32) << 7 | event.data1 as i32) - 8192;
+                let semitones = (value as f32 / 8192.0) * self.bend_range_semitones;
+                let ratio = 2f32.powf(semitones / 12.0);
+                match self.bend_scope {
+                    BendScope::Global => self.bend_ratio = [ratio; N_CHANNELS],
+                    BendScope::PerNote => self.bend_ratio[channel as usize] = ratio,
+                }
+                debug!("Pitch bend set to {} semitones", semitones);
+            }
+            0xA0 => {
+                // Polyphonic Aftertouch: opens the filter of the matching voice
+                let channel = event.status & 0x0F;
+                let note = event.data1;
+                let pressure = event.data2 as f32 / 127.0;
+                for v in self.voices.iter_mut() {
+                    if v.note == note && v.channel == channel && v.gate {
+                        v.aftertouch = pressure;
+                    }
+                }
+            }
+            0xD0 => {
+                // Channel Pressure: opens the filter of every voice
+                self.channel_pressure = event.data1 as f32 / 127.0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Release the previous arp note and play the next one
+    fn arp_step(&mut self) {
+        let (off, on) = self.arp.step();
+        if let Some(n) = off {
+            self.release_note(n.note, n.channel);
+            midi_out::send(MidiEvent {
+                status: 0x80 | n.channel,
+                data1: n.note,
+                data2: 0,
+            });
+        }
+        if let Some(n) = on {
+            self.play_note(n.note, n.channel, n.vel_amp);
+            // also send the arpeggio out, to play other instruments
+            midi_out::send(MidiEvent {
+                status: 0x90 | n.channel,
+                data1: n.note,
+                data2: ((n.vel_amp * 127.0) as u8).clamp(1, 127),
+            });
+        }
+    }
+
+    /// Render the voices and master effects into `buf`
+    fn render(&mut self, buf: &mut [u32]) {
+        // Polyphonic synth rendering
+        let lfo_rate_hz = if self.clock.stopped {
+            0.0
+        } else {
+            self.lfo_rate_hz()
+        };
+        self.sample_count = self
+            .sample_count
+            .wrapping_add((buf.len() / WORDS_PER_FRAME) as u32);
+        let gain_target = if self.muted { 0.0 } else { self.volume };
+        let gain_step = 1.0 / (MUTE_RAMP_S * SAMPLE_RATE as f32);
+        let mix_norm_rate = rc_rate(MIX_NORM_SMOOTH_S * SAMPLE_RATE as f32);
+        let sustain_rate = rc_rate(SUSTAIN_SMOOTH_S * SAMPLE_RATE as f32);
+        let voice_render = self.voice_render();
+
+        // Render audio: sum voices
+        for frame in buf.chunks_exact_mut(WORDS_PER_FRAME) {
+            // Vibrato: a shared low-rate sine, scaled by the mod wheel
+            self.vibrato_phase += lfo_rate_hz / (SAMPLE_RATE as f32);
+            if self.vibrato_phase >= 1.0 {
+                self.vibrato_phase -= 1.0;
+            }
+            let vibrato = if self.mod_wheel > 0.0 {
+                let lfo = (2.0 * core::f32::consts::PI * self.vibrato_phase).sin();
+                2f32.powf(lfo * self.mod_wheel * VIBRATO_MAX_DEPTH_SEMITONES / 12.0)
+            } else {
+                1.0
+            };
+
+            for (smoothed, p) in self.sustain_smoothed.iter_mut().zip(self.patches.iter()) {
+                *smoothed += (p.sustain_level - *smoothed) * sustain_rate;
+            }
+
+            let (mix_l, mix_r, n_sounding) = voice_render.mix(
+                &mut self.voices,
+                &self.active,
+                &self.patches,
+                &self.sustain_smoothed,
+                &self.bend_ratio,
+                vibrato,
+            );
+            #[cfg(feature = "preset-crossfade")]
+            let (mix_l, mix_r) = self.crossfade(mix_l, mix_r, vibrato);
+
+            // normalize the mix by the square root of the number of sounding voices,
+            // so single notes aren't quiet and chords don't jump in level. The divisor
+            // is smoothed, so voices starting and ending don't cause zipper noise.
+            let norm_target = 1.0 / (n_sounding.max(1) as f32).sqrt();
+            self.mix_norm += (norm_target - self.mix_norm) * mix_norm_rate;
+            let mix_norm_l = mix_l * self.mix_norm;
+            let mix_norm_r = mix_r * self.mix_norm;
+
+            // master effects
+            let (mix_norm_l, mix_norm_r) = self.effects.process(mix_norm_l, mix_norm_r);
+
+            // ramp towards the volume, or silence when muted, without zipper noise
+            if self.master_gain < gain_target {
+                self.master_gain = (self.master_gain + gain_step).min(gain_target);
+            } else if self.master_gain > gain_target {
+                self.master_gain = (self.master_gain - gain_step).max(gain_target);
+            }
+
+            let gain = MAX_AMPLITUDE * self.master_gain;
+            let out_l = gain * mix_norm_l;
+            let out_r = gain * mix_norm_r;
+            // for single speaker setups, the same mono sum on both channels
+            #[cfg(feature = "mono-out")]
+            let (out_l, out_r) = {
+                let sum = (out_l + out_r) * MONO_SUM_GAIN;
+                (sum, sum)
+            };
+            #[cfg(feature = "metering")]
+            self.meter.add(out_l, out_r);
+            write_frame(frame, out_l, out_r, self.sample_format);
+        }
+        self.active.retain(|&idx| self.voices[idx].active());
+        #[cfg(feature = "metering")]
+        self.meter.report();
+    }
+
+    /// Mix in the voices from before a preset change, if they're still fading out. The
+    /// crossfade is equal-power, from the old voices to the new ones.
+    #[cfg(feature = "preset-crossfade")]
+    fn crossfade(&mut self, mix_l: f32, mix_r: f32, vibrato: f32) -> (f32, f32) {
+        let xf = &mut self.crossfade;
+        let Some((render, pos)) = &mut xf.fade else {
+            return (mix_l, mix_r);
+        };
+        let (old_l, old_r, _) = render.mix(
+            &mut xf.voices,
+            &xf.active,
+            &xf.patches,
+            &self.sustain_smoothed,
+            &self.bend_ratio,
+            vibrato,
+        );
+        let angle = *pos as f32 / PRESET_XFADE_FRAMES as f32 * core::f32::consts::FRAC_PI_2;
+        let (old_gain, new_gain) = (angle.cos(), angle.sin());
+        *pos += 1;
+        if *pos >= PRESET_XFADE_FRAMES {
+            xf.fade = None;
+        }
+        (
+            mix_l * new_gain + old_l * old_gain,
+            mix_r * new_gain + old_r * old_gain,
+        )
+    }
+
+    /// Keep the voices as they are, to fade out after a preset change. A preset change
+    /// while a crossfade is running changes the voices fading in, and the crossfade
+    /// carries on, so no more than two sets of voices are ever rendered.
+    #[cfg(feature = "preset-crossfade")]
+    fn start_crossfade(&mut self) {
+        if self.crossfade.fade.is_some() || self.active.is_empty() {
+            return;
+        }
+        // Copied straight into the crossfade's own arrays. Building a `Crossfade` and
+        // moving it in would put another copy of the voices on core 1's stack.
+        let render = self.voice_render();
+        let xf = &mut self.crossfade;
+        xf.voices.copy_from_slice(&self.voices);
+        xf.active.clone_from(&self.active);
+        xf.patches.copy_from_slice(&self.patches);
+        xf.fade = Some((render, 0));
+    }
+
+    /// The settings the voices are currently rendered with
+    fn voice_render(&self) -> VoiceRender {
+        // The second oscillator only runs when it's mixed in. When it is, the two
+        // oscillators are spread symmetrically around the note frequency.
+        let osc2_on = self.osc_mix > 0.0;
+        let (osc1_ratio, osc2_ratio) = if osc2_on && self.osc_detune_cents > 0.0 {
+            let half_detune = 2f32.powf(self.osc_detune_cents / 2400.0);
+            (1.0 / half_detune, half_detune)
+        } else {
+            (1.0, 1.0)
+        };
+        VoiceRender {
+            env_curve: self.env_curve,
+            ad_mode: self.ad_mode,
+            glide_curve: self.glide_curve,
+            filter_model: self.filter_model,
+            osc2_on,
+            osc1_ratio,
+            osc2_ratio,
+            osc_mix: self.osc_mix,
+            osc_sync: self.osc_sync,
+            ring_mod: self.ring_mod,
+            noise_mix: self.noise_mix,
+            sub_level: self.sub_level,
+            filter_env_amount: self.filter_env_amount,
+            filter_keytrack: self.filter_keytrack,
+            vel_to_cutoff: self.vel_to_cutoff,
+            channel_pressure: self.channel_pressure,
+            fm_ratio: self.fm_ratio,
+            fm_index: self.fm_index,
+            fm_env_rate: rc_rate(self.fm_decay_s * SAMPLE_RATE as f32),
+            cutoff_rate: rc_rate(CUTOFF_SMOOTH_S * SAMPLE_RATE as f32),
+            tune_ratio: 2f32.powf(self.fine_tune_cents / 1200.0),
+            phase_scale: phase_scale(SAMPLE_RATE),
+        }
+    }
+
+    /// Set how samples are encoded for the DAC
+    #[cfg_attr(feature = "pwm-audio", allow(dead_code))]
+    pub fn set_sample_format(&mut self, format: SampleFormat) {
+        self.sample_format = format;
+    }
+
+    /// Log the sound parameters and the sounding voices (MIDI CC 118), to see what a
+    /// patch is actually set to without a debugger
+    pub fn debug_dump(&self) {
+        for (i, p) in self.patches.iter().enumerate() {
+            info!(
+                "Patch {}: {} (pulse width {}, wavetable {}, morph {}), attack {} s, decay {} s, sustain {}, release {} s",
+                i,
+                p.waveform,
+                p.pulse_width,
+                p.wavetable_pos,
+                p.morph_pos,
+                p.attack_time_s,
+                p.decay_time_s,
+                p.sustain_level,
+                p.release_time_s
+            );
+            info!(
+                "Patch {}: {} filter at {} Hz, resonance {}",
+                i,
+                p.filter_type,
+                cutoff_hz(p.filter_cutoff),
+                p.filter_resonance
+            );
+        }
+        info!(
+            "Oscillators: mix {}, detune {} cents, sync {}, ring mod {}, noise {}, sub {}",
+            self.osc_mix,
+            self.osc_detune_cents,
+            self.osc_sync,
+            self.ring_mod,
+            self.noise_mix,
+            self.sub_level
+        );
+        info!(
+            "FM: ratio {}, index {}, decay {} s",
+            self.fm_ratio, self.fm_index, self.fm_decay_s
+        );
+        info!(
+            "Filter: {} model, envelope {}, keytrack {}, velocity {}",
+            self.filter_model, self.filter_env_amount, self.filter_keytrack, self.vel_to_cutoff
+        );
+        info!(
+            "Envelope: {} curve, AD mode {}, keytrack {}, velocity to attack {}",
+            self.env_curve, self.ad_mode, self.env_keytrack, self.vel_to_attack
+        );
+        info!(
+            "Voices: {} mode, mono {}, unison {}, volume {}, muted {}, pan spread {}",
+            self.multi_mode,
+            self.mono,
+            self.unison_count,
+            self.volume,
+            self.muted,
+            self.keyboard_pan_spread
+        );
+        info!(
+            "Tuning: transpose {} semitones, fine tune {} cents, bend range {} semitones",
+            self.transpose_semitones, self.fine_tune_cents, self.bend_range_semitones
+        );
+        let mut n_active = 0;
+        for (i, v) in self.voices.iter().enumerate().filter(|(_, v)| v.active()) {
+            n_active += 1;
+            info!(
+                "Voice {}: note {} channel {} patch {}, {} at {}",
+                i,
+                v.note,
+                v.channel + 1,
+                v.group,
+                v.stage,
+                v.env
+            );
+        }
+        info!("{} of {} voices active", n_active, N_VOICES);
+    }
+
+    /// Envelope stage of a voice and how many samples it has spent in it, for
+    /// debugging envelope settings
+    #[allow(dead_code)]
+    pub fn voice_stage_info(&self, idx: usize) -> (EnvStage, u32) {
+        let v = &self.voices[idx];
+        (v.stage, v.stage_samples)
+    }
+
+    /// Rebuild the oscillators of the voices using `patch`, after a waveform or
+    /// waveform parameter change
+    fn update_oscillators(&mut self, patch: usize) {
+        for v in self.voices.iter_mut().filter(|v| v.group == patch) {
+            let was_plucked = matches!(v.osc, Osc::PluckedString);
+            v.osc = self.patches[patch].osc(v.rng.rotate_left(16));
+            // a held note switched to the string would be silent until it's plucked
+            if v.gate && !was_plucked && matches!(v.osc, Osc::PluckedString) {
+                v.pluck();
+            }
+        }
+    }
+
+    /// Set every parameter held by `preset`, on `patch`
+    fn apply_preset(&mut self, patch: usize, preset: &Preset) {
+        let p = &mut self.patches[patch];
+        p.waveform = preset.waveform;
+        p.attack_time_s = preset.attack_time_s;
+        p.decay_time_s = preset.decay_time_s;
+        p.sustain_level = preset.sustain_level;
+        p.release_time_s = preset.release_time_s;
+        p.filter_cutoff = preset.filter_cutoff;
+        p.filter_resonance = preset.filter_resonance;
+        p.update_filter_coefficients();
+        self.update_oscillators(patch);
+        self.osc_detune_cents = preset.osc_detune_cents;
+        self.osc_mix = preset.osc_mix;
+    }
+
+    /// The sound parameters of both patches and the shared voice settings, as a
+    /// versioned block that `load_params` can restore. Performance state (mod wheel,
+    /// bend, volume) and the master effects aren't included.
+    pub fn save_params(&self) -> Params {
+        let mut w = ParamWriter {
+            buf: [0; PARAMS_LEN],
+            pos: 0,
+        };
+        w.u8(PARAMS_VERSION);
+        for p in self.patches.iter() {
+            w.index(&WAVEFORMS, p.waveform);
+            w.f32(p.pulse_width);
+            w.f32(p.wavetable_pos);
+            w.f32(p.attack_time_s);
+            w.f32(p.decay_time_s);
+            w.f32(p.sustain_level);
+            w.f32(p.release_time_s);
+            w.f32(p.filter_cutoff);
+            w.f32(p.filter_resonance);
+            w.index(&FILTER_TYPES, p.filter_type);
+        }
+        w.index(&MULTI_MODES, self.multi_mode);
+        w.u8(self.split_note);
+        w.u8(self.unison_count as u8);
+        w.f32(self.unison_detune_cents);
+        w.bool(self.random_phase);
+        w.f32(self.osc_detune_cents);
+        w.f32(self.osc_mix);
+        w.bool(self.osc_sync);
+        w.bool(self.ring_mod);
+        w.f32(self.noise_mix);
+        w.f32(self.sub_level);
+        w.index(&ENV_CURVES, self.env_curve);
+        w.index(&RETRIGGERS, self.retrigger);
+        w.index(&VELOCITY_CURVES, self.velocity_curve);
+        w.f32(self.lfo_keytrack);
+        w.f32(self.filter_env_amount);
+        w.f32(self.filter_keytrack);
+        w.f32(self.vel_to_cutoff);
+        w.f32(self.portamento_time_s);
+        w.bool(self.portamento);
+        w.f32(self.env_keytrack);
+        w.index(&FILTER_MODELS, self.filter_model);
+        w.bool(self.ad_mode);
+        w.index(&STEAL_POLICIES, self.steal_policy);
+        for p in self.patches.iter() {
+            w.f32(p.morph_pos);
+        }
+        w.index(&GLIDE_CURVES, self.glide_curve);
+        w.f32(self.vel_to_attack);
+        w.f32(self.keyboard_pan_spread);
+        w.f32(self.fm_ratio);
+        w.f32(self.fm_index);
+        w.f32(self.fm_decay_s);
+        w.buf
+    }
+
+    /// Restore the parameters saved by `save_params`. Returns false, changing
+    /// nothing, if the block has a different layout version.
+    pub fn load_params(&mut self, params: &Params) -> bool {
+        let mut r = ParamReader {
+            buf: params,
+            pos: 0,
+        };
+        if r.u8() != PARAMS_VERSION {
+            warn!("Ignoring parameters with unknown version {}", params[0]);
+            return false;
+        }
+        for patch in 0..N_PATCHES {
+            let p = &mut self.patches[patch];
+            p.waveform = r.index(&WAVEFORMS);
+            p.pulse_width = r.f32(0.05, 0.95);
+            p.wavetable_pos = r.f32(0.0, 1.0);
+            p.attack_time_s = r.f32(0.001, 2.0);
+            p.decay_time_s = r.f32(0.001, 2.0);
+            p.sustain_level = r.f32(0.0, 1.0);
+            p.release_time_s = r.f32(0.001, 3.0);
+            p.filter_cutoff = r.f32(0.0, 1.0);
+            p.filter_resonance = r.f32(0.0, 4.0);
+            p.filter_type = r.index(&FILTER_TYPES);
+            p.update_filter_coefficients();
+            self.update_oscillators(patch);
+        }
+        self.multi_mode = r.index(&MULTI_MODES);
+        self.split_note = r.u8().min(127);
+        self.unison_count = (r.u8() as usize).clamp(1, 4);
+        self.unison_detune_cents = r.f32(0.0, 50.0);
+        self.random_phase = r.bool();
+        self.osc_detune_cents = r.f32(0.0, 50.0);
+        self.osc_mix = r.f32(0.0, 1.0);
+        self.osc_sync = r.bool();
+        self.ring_mod = r.bool();
+        self.noise_mix = r.f32(0.0, 1.0);
+        self.sub_level = r.f32(0.0, 1.0);
+        self.env_curve = r.index(&ENV_CURVES);
+        self.retrigger = r.index(&RETRIGGERS);
+        self.velocity_curve = r.index(&VELOCITY_CURVES);
+        self.lfo_keytrack = r.f32(0.0, 1.0);
+        self.filter_env_amount = r.f32(0.0, 1.0);
+        self.filter_keytrack = r.f32(0.0, 1.0);
+        self.vel_to_cutoff = r.f32(0.0, 1.0);
+        self.portamento_time_s = r.f32(0.0, 2.0);
+        self.portamento = r.bool();
+        self.env_keytrack = r.f32(0.0, 1.0);
+        self.filter_model = r.index(&FILTER_MODELS);
+        self.ad_mode = r.bool();
+        self.steal_policy = r.index(&STEAL_POLICIES);
+        for patch in 0..N_PATCHES {
+            self.patches[patch].morph_pos = r.f32(0.0, 1.0);
+            self.update_oscillators(patch);
+        }
+        self.glide_curve = r.index(&GLIDE_CURVES);
+        self.vel_to_attack = r.f32(0.0, 1.0);
+        self.update_env_scaling();
+        self.keyboard_pan_spread = r.f32(0.0, 1.0);
+        self.update_pans();
+        self.fm_ratio = r.f32(0.5, FM_MAX_RATIO);
+        self.fm_index = r.f32(0.0, FM_MAX_INDEX);
+        self.fm_decay_s = r.f32(0.001, 4.0);
+        true
+    }
+
+    // Re-pan the voices, after a channel's pan or the keyboard pan spread changes
+    fn update_pans(&mut self) {
+        for v in self.voices.iter_mut() {
+            let channel_pan = self.channel_pan[v.channel as usize];
+            v.set_pan(note_pan(channel_pan, self.keyboard_pan_spread, v.note));
+        }
+    }
+
+    // Set the parameter chosen by RPN/NRPN from a Data Entry MSB and LSB
+    fn data_entry(&mut self, msb: u8, lsb: u8) {
+        match self.param_number {
+            ParamNumber::Rpn(0, 0) => {
+                // Pitch bend sensitivity: semitones, and cents in the LSB
+                self.bend_range_semitones = (msb as f32 + lsb.min(99) as f32 / 100.0)
+                    .min(MAX_PITCH_BEND_RANGE_SEMITONES as f32);
+                debug!(
+                    "Pitch bend range set to {} semitones",
+                    self.bend_range_semitones
+                );
+            }
+            ParamNumber::Rpn(0, 1) => {
+                // Fine tuning: 14 bits centred on 8192, the full range +/-100 cents.
+                // Sounding notes follow it.
+                let value = ((msb as i32) << 7 | lsb as i32) - 8192;
+                self.fine_tune_cents = value as f32 / 8192.0 * 100.0;
+                debug!("Fine tune set to {} cents", self.fine_tune_cents);
+            }
+            ParamNumber::Rpn(0, 2) => {
+                // Coarse tuning: transpose in semitones, 64 is centred. The LSB isn't
+                // used. It applies from the next note on.
+                self.transpose_semitones = msb as i8 - 64;
+                debug!("Transpose set to {} semitones", self.transpose_semitones);
+            }
+            ParamNumber::Rpn(param_msb, param_lsb) | ParamNumber::Nrpn(param_msb, param_lsb) => {
+                debug!(
+                    "Ignored data entry for parameter {}/{}",
+                    param_msb, param_lsb
+                );
+            }
+            ParamNumber::None => {}
+        }
+    }
+
+    // Volume from a 0.0 to 1.0 control value, squared for a roughly even loudness taper
+    fn set_volume(&mut self, level: f32) {
+        self.volume = level * level;
+        debug!("Volume set to {}", self.volume);
+    }
+
+    // Filter cutoff from a 0.0 to 1.0 control value, exponentially 20 Hz - 18 kHz
+    fn set_filter_cutoff(&mut self, patch: usize, cutoff: f32) {
+        self.patches[patch].filter_cutoff = cutoff;
+        self.patches[patch].update_filter_coefficients();
+        debug!("Filter cutoff set to {} Hz", cutoff_hz(cutoff));
+    }
+
+    // Apply a patch's envelope times to its voices that are partway through their
+    // envelopes, so changes are heard on held notes. Voices fading out to be stolen
+    // keep their fast release.
+    fn update_envelope_times(&mut self, patch: usize) {
+        let p = &self.patches[patch];
+        for v in self.voices.iter_mut().filter(|v| v.group == patch) {
+            match v.stage {
+                EnvStage::Attack => {
+                    v.set_attack_time(p.attack_time_s);
+                    v.set_decay_time(p.decay_time_s);
+                }
+                EnvStage::Decay => v.set_decay_time(p.decay_time_s),
+                EnvStage::Release if v.pending.is_none() => v.set_release_time(p.release_time_s),
+                _ => {}
+            }
+        }
+    }
+
+    // The voices keep their own copy of the envelope keytracking and velocity to attack,
+    // for working out their stage times
+    fn update_env_scaling(&mut self) {
+        for v in self.voices.iter_mut() {
+            v.env_keytrack = self.env_keytrack;
+            v.vel_to_attack = self.vel_to_attack;
+        }
+    }
+
+    /// Patch edited by CCs and Program Changes on `channel`
+    fn channel_group(&self, channel: u8) -> usize {
+        if self.multi_mode != MultiMode::Off && channel == 1 {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Restore the default mod wheel, ADSR and filter settings
+    fn reset_controllers(&mut self) {
+        self.mod_wheel = 0.0;
+        self.bend_ratio = [1.0; N_CHANNELS];
+        for p in self.patches.iter_mut() {
+            p.attack_time_s = DEFAULT_ATTACK_TIME_S;
+            p.decay_time_s = DEFAULT_DECAY_TIME_S;
+            p.sustain_level = DEFAULT_SUSTAIN_LEVEL;
+            p.release_time_s = DEFAULT_RELEASE_TIME_S;
+            p.filter_cutoff = DEFAULT_FILTER_CUTOFF;
+            p.filter_resonance = DEFAULT_FILTER_RESONANCE;
+            p.update_filter_coefficients();
+        }
+        self.filter_env_amount = 0.0;
+        self.filter_keytrack = 0.0;
+        self.vel_to_cutoff = 0.0;
+        self.vel_to_attack = 0.0;
+        self.update_env_scaling();
+        self.channel_pressure = 0.0;
+        self.param_number = ParamNumber::None;
+        self.data_entry_msb = 0;
+    }
+
+    /// Frequency of a played note, after the transpose. The transposed note is kept in
+    /// the MIDI range, so notes transposed past either end stick at it.
+    fn note_freq(&self, note: u8) -> f32 {
+        let note = (note as i32 + self.transpose_semitones as i32).clamp(0, 127);
+        self.tuning.note_to_freq(note as u8)
+    }
+
+    /// Start a note, on free voices if there are any, otherwise stealing one according
+    /// to the stealing policy. In unison, the note gets as many detuned voices as are free, up to
+    /// `unison_count`. Voices of a unison group share the note and age, so they're
+    /// released (and stolen oldest-first) together.
+    fn play_note(&mut self, note: u8, channel: u8, vel_amp: f32) {
+        self.last_note = note;
+        match self.multi_mode {
+            MultiMode::Off => self.play_note_on_patch(note, channel, vel_amp, 0),
+            MultiMode::Split => {
+                let patch = if note < self.split_note { 0 } else { 1 };
+                self.play_note_on_patch(note, channel, vel_amp, patch);
+            }
+            MultiMode::Layer => {
+                self.play_note_on_patch(note, channel, vel_amp, 0);
+                self.play_note_on_patch(note, channel, vel_amp, 1);
+            }
+            MultiMode::Channel => {
+                let patch = self.channel_group(channel);
+                self.play_note_on_patch(note, channel, vel_amp, patch);
+            }
+        }
+        self.last_freq = Some(self.note_freq(note));
+    }
+
+    fn play_note_on_patch(&mut self, note: u8, channel: u8, vel_amp: f32, patch: usize) {
+        let freq = self.note_freq(note);
+        let n_free = self.voices[..self.polyphony]
+            .iter()
+            .filter(|v| !v.active())
+            .count();
+        let n_unison = self.unison_count.min(n_free);
+        let n_playing = self
+            .voices
+            .iter()
+            .filter(|v| v.plays(note, channel, patch))
+            .count();
+        let p = self.patches[patch];
+        let sustain_level = if self.ad_mode { 0.0 } else { p.sustain_level };
+        let glide_from = if self.portamento {
+            self.last_freq
+        } else {
+            None
+        };
+        if self.mono {
+            self.mono_note_on(note, channel, freq, vel_amp, patch);
+        } else if n_playing > 0 {
+            // a second note on without a note off in between retriggers the voices
+            // already playing the note, rather than stacking more on top of them
+            self.age_counter = self.age_counter.wrapping_add(1);
+            let unison_amp = vel_amp / (n_playing as f32).sqrt();
+            for v in self
+                .voices
+                .iter_mut()
+                .filter(|v| v.plays(note, channel, patch))
+            {
+                let freq = v.target_freq;
+                v.start_with_adsr(
+                    note,
+                    freq,
+                    unison_amp,
+                    self.age_counter,
+                    p.attack_time_s,
+                    p.decay_time_s,
+                    sustain_level,
+                    self.random_phase,
+                    self.retrigger,
+                );
+            }
+        } else if n_unison > 0 {
+            self.age_counter = self.age_counter.wrapping_add(1);
+            let pan = note_pan(
+                self.channel_pan[channel as usize],
+                self.keyboard_pan_spread,
+                note,
+            );
+            // keep the overall level roughly the same however many voices are stacked
+            let unison_amp = vel_amp / (n_unison as f32).sqrt();
+            for k in 0..n_unison {
+                let Some(idx) = self.voices[..self.polyphony]
+                    .iter()
+                    .position(|v| !v.active())
+                else {
+                    break;
+                };
+                let spread = if n_unison > 1 {
+                    k as f32 / (n_unison - 1) as f32 - 0.5
+                } else {
+                    0.0
+                };
+                let detune = 2f32.powf(spread * self.unison_detune_cents / 1200.0);
+                self.voices[idx].set_patch(patch, &p);
+                self.voices[idx].start_with_adsr(
+                    note,
+                    freq * detune,
+                    unison_amp,
+                    self.age_counter,
+                    p.attack_time_s,
+                    p.decay_time_s,
+                    sustain_level,
+                    self.random_phase,
+                    self.retrigger,
+                );
+                if let Some(from) = glide_from {
+                    self.voices[idx].glide_from(from * detune, self.portamento_time_s);
+                }
+                self.voices[idx].set_channel(channel, pan);
+                self.activate(idx);
+            }
+        } else {
+            // steal a voice, fading it out first
+            if let Some(idx) = self.steal_victim() {
+                self.age_counter = self.age_counter.wrapping_add(1);
+                self.voices[idx].steal(PendingNote {
+                    note,
+                    channel,
+                    pan: note_pan(
+                        self.channel_pan[channel as usize],
+                        self.keyboard_pan_spread,
+                        note,
+                    ),
+                    freq,
+                    vel_amp,
+                    age: self.age_counter,
+                    attack_s: p.attack_time_s,
+                    decay_s: p.decay_time_s,
+                    sustain_level,
+                    random_phase: self.random_phase,
+                    group: patch,
+                    osc: p.osc(self.age_counter),
+                    glide_from,
+                    portamento_s: self.portamento_time_s,
+                });
+            }
+        }
+    }
+
+    /// Add a voice that's starting a note to the active list, if it isn't already there.
+    /// Stolen voices are still fading out, so they're already on it.
+    fn activate(&mut self, idx: usize) {
+        if !self.active.contains(&idx) {
+            // can't fail, as the list has room for every voice
+            let _ = self.active.push(idx);
+        }
+    }
+
+    /// The voice to steal for a new note, or `None` if the policy is not to steal. Voices
+    /// already stolen for another note are skipped, so each note of a chord gets its own.
+    fn steal_victim(&self) -> Option<usize> {
+        let voices = self.voices[..self.polyphony]
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.pending.is_none());
+        let victim = match self.steal_policy {
+            StealPolicy::Oldest => voices.min_by_key(|(_, v)| v.age),
+            StealPolicy::Quietest => voices.min_by(|(_, a), (_, b)| {
+                let a_held = a.stage != EnvStage::Release;
+                let b_held = b.stage != EnvStage::Release;
+                a_held.cmp(&b_held).then(a.env.total_cmp(&b.env))
+            }),
+            StealPolicy::LowestNote => voices.min_by_key(|(_, v)| v.note),
+            StealPolicy::None => None,
+        };
+        if victim.is_none() {
+            debug!("No voice free, note dropped");
+        }
+        victim.map(|(idx, _)| idx)
+    }
+
+    /// Release a note played on `channel`
+    fn release_note(&mut self, note: u8, channel: u8) {
+        if self.mono {
+            self.mono_note_off(note, channel);
+        } else {
+            for v in self.voices.iter_mut() {
+                v.release_note(note, channel, self.patches[v.group].release_time_s);
+            }
+        }
+    }
+
+    /// A key has been let go, by either form of note off. With the arpeggiator running,
+    /// this also releases the sounding arp note once no keys are held.
+    fn handle_note_off(&mut self, note: u8, channel: u8) {
+        if self.latched.contains(&(note, channel)) {
+            return;
+        }
+        if self.arp.enabled
+            && let Some(arp_note) = self.arp.note_off(note)
+        {
+            self.release_note(arp_note.note, arp_note.channel);
+        }
+        self.release_note(note, channel);
+    }
+
+    /// Latch a note that's being played. If it's already latched it's let go instead,
+    /// and this returns false so it isn't played again.
+    fn latch_note(&mut self, note: u8, channel: u8) -> bool {
+        if let Some(pos) = self.latched.iter().position(|&n| n == (note, channel)) {
+            self.latched.remove(pos);
+            self.handle_note_off(note, channel);
+            return false;
+        }
+        if self.latched.is_full() {
+            let (note, channel) = self.latched.remove(0);
+            self.handle_note_off(note, channel);
+        }
+        let _ = self.latched.push((note, channel));
+        true
+    }
+
+    /// Mono mode note on: legato notes glide on voice 0 without retriggering
+    fn mono_note_on(&mut self, note: u8, channel: u8, freq: f32, vel_amp: f32, patch: usize) {
+        if let Some(pos) = self.held_notes.iter().position(|&n| n == note) {
+            self.held_notes.remove(pos);
+        }
+        if self.held_notes.is_full() {
+            self.held_notes.remove(0);
+        }
+        let _ = self.held_notes.push(note);
+
+        let pan = note_pan(
+            self.channel_pan[channel as usize],
+            self.keyboard_pan_spread,
+            note,
+        );
+        let v = &mut self.voices[0];
+        v.set_channel(channel, pan);
+        if v.gate {
+            v.note = note;
+            v.glide_to(freq, self.portamento_time_s);
+        } else {
+            self.age_counter = self.age_counter.wrapping_add(1);
+            let p = &self.patches[patch];
+            let sustain_level = if self.ad_mode { 0.0 } else { p.sustain_level };
+            v.set_patch(patch, p);
+            v.start_with_adsr(
+                note,
+                freq,
+                vel_amp,
+                self.age_counter,
+                p.attack_time_s,
+                p.decay_time_s,
+                sustain_level,
+                self.random_phase,
+                self.retrigger,
+            );
+            if self.portamento
+                && let Some(from) = self.last_freq
+            {
+                v.glide_from(from, self.portamento_time_s);
+            }
+            self.activate(0);
+        }
+    }
+
+    /// Mono mode note off: fall back to the most recent held note, if any
+    fn mono_note_off(&mut self, note: u8, channel: u8) {
+        if let Some(pos) = self.held_notes.iter().position(|&n| n == note) {
+            self.held_notes.remove(pos);
+        }
+        let v = &mut self.voices[0];
+        if v.note != note || v.channel != channel || !v.gate {
+            return;
+        }
+        match self.held_notes.last() {
+            Some(&prev) => {
+                v.note = prev;
+                let freq = self.note_freq(prev);
+                self.voices[0].glide_to(freq, self.portamento_time_s);
+            }
+            None => v.note_off(self.patches[v.group].release_time_s),
+        }
+    }
+
+    /// Write the playing calibration signal instead of rendering voices, fading it in, or
+    /// out if another signal has been selected
+    fn render_test_signal(&mut self, buf: &mut [u32]) {
+        let peak = match self.test_signal {
+            TestSignal::FullScaleSine => i16::MAX as f32,
+            TestSignal::Tone => 3277.0,
+            TestSignal::Dc => 16384.0,
+            TestSignal::Off => return,
+        };
+        let target_gain = if self.test_signal == self.test_target {
+            1.0
+        } else {
+            0.0
+        };
+        let fade_step = 1.0 / (TEST_SIGNAL_FADE_S * SAMPLE_RATE as f32);
+
+        let delta = 2.0 * core::f32::consts::PI * TEST_SIGNAL_FREQ_HZ / (SAMPLE_RATE as f32);
+        let (cos_d, sin_d) = (delta.cos(), delta.sin());
+        let (mut c, mut s) = self.test_osc;
+        for frame in buf.chunks_exact_mut(WORDS_PER_FRAME) {
+            self.test_gain += (target_gain - self.test_gain).clamp(-fade_step, fade_step);
+            let level = if self.test_signal == TestSignal::Dc {
+                1.0
+            } else {
+                s
+            };
+            let sample = level * peak * self.test_gain;
+            write_frame(frame, sample, sample, self.sample_format);
+            (c, s) = (c * cos_d - s * sin_d, s * cos_d + c * sin_d);
+            // one Newton step back onto the unit circle, so the amplitude doesn't drift
+            let k = 1.5 - 0.5 * (c * c + s * s);
+            (c, s) = (c * k, s * k);
+        }
+        self.test_osc = (c, s);
+    }
+
+    /// LFO rate, scaled by the most recent note according to `lfo_keytrack`. With
+    /// MIDI clock the LFO runs at one cycle per eighth note.
+    fn lfo_rate_hz(&self) -> f32 {
+        let rate_hz = match self.clock.tempo_bpm() {
+            Some(bpm) => bpm / 30.0,
+            None => VIBRATO_RATE_HZ,
+        };
+        if self.lfo_keytrack > 0.0 {
+            let semitones = self.last_note as f32 - LFO_KEYTRACK_REF_NOTE as f32;
+            rate_hz * 2f32.powf(self.lfo_keytrack * semitones / 12.0)
+        } else {
+            rate_hz
+        }
+    }
+}
+
+/// The settings voices are rendered with, gathered once per render. They're kept apart
+/// from `Synth`, so a second set of voices can be rendered with other settings.
+#[derive(Copy, Clone)]
+struct VoiceRender {
+    env_curve: EnvCurve,
+    ad_mode: bool,
+    glide_curve: GlideCurve,
+    filter_model: FilterModel,
+    osc2_on: bool,
+    osc1_ratio: f32, // oscillator frequency ratios, for the detune
+    osc2_ratio: f32,
+    osc_mix: f32,
+    osc_sync: bool,
+    ring_mod: bool,
+    noise_mix: f32,
+    sub_level: f32,
+    fm_ratio: f32,
+    fm_index: f32,
+    fm_env_rate: f32, // per-sample decay of the modulator's envelope
+    filter_env_amount: f32,
+    filter_keytrack: f32,
+    vel_to_cutoff: f32,
+    channel_pressure: f32,
+    cutoff_rate: f32,
+    tune_ratio: f32,  // fine tune frequency ratio
+    phase_scale: f32, // oscillator phase increment per Hz
+}
+
+impl VoiceRender {
+    /// Advance the voices on the `active` list by one sample, returning the left and
+    /// right mix and how many voices are sounding
+    fn mix(
+        &self,
+        voices: &mut [Voice; N_VOICES],
+        active: &[usize],
+        patches: &[Patch; N_PATCHES],
+        sustain_smoothed: &[f32; N_PATCHES],
+        bend_ratio: &[f32; N_CHANNELS],
+        vibrato: f32,
+    ) -> (f32, f32, usize) {
+        let mut mix_l: f32 = 0.0;
+        let mut mix_r: f32 = 0.0;
+        let mut n_sounding = 0;
+        for &idx in active {
+            let v = &mut voices[idx];
+            // voices that have finished this buffer are skipped until they're pruned
+            if v.stage == EnvStage::Idle && v.env <= 0.0 {
+                continue;
+            }
+            n_sounding += 1;
+
+            // envelope state machine
+            let prev_stage = v.stage;
+            match v.stage {
+                EnvStage::Idle => {
+                    // nothing
+                }
+                EnvStage::Attack => {
+                    let end = match self.env_curve {
+                        EnvCurve::Linear => {
+                            v.env += v.attack_inc;
+                            v.target_amp
+                        }
+                        EnvCurve::Exponential => {
+                            v.env += (v.target_amp - v.env) * v.attack_rate;
+                            v.target_amp * (1.0 - EXP_ENV_THRESHOLD)
+                        }
+                    };
+                    if v.env >= end {
+                        v.env = v.target_amp;
+                        v.stage = EnvStage::Decay;
+                    }
+                }
+                EnvStage::Decay => {
+                    // in AD mode the decay carries on down to silence
+                    let sustain_level = if self.ad_mode {
+                        0.0
+                    } else {
+                        v.sustain_level = sustain_smoothed[v.group];
+                        v.sustain_level * v.target_amp
+                    };
+                    let end = match self.env_curve {
+                        EnvCurve::Linear => {
+                            v.env -= v.decay_inc;
+                            sustain_level
+                        }
+                        EnvCurve::Exponential => {
+                            v.env -= (v.env - sustain_level) * v.decay_rate;
+                            sustain_level + v.target_amp * EXP_ENV_THRESHOLD
+                        }
+                    };
+                    if v.env <= end {
+                        v.env = sustain_level;
+                        if self.ad_mode {
+                            v.stage = EnvStage::Idle;
+                            v.gate = false;
+                        } else {
+                            v.stage = EnvStage::Sustain;
+                        }
+                    }
+                }
+                EnvStage::Sustain => {
+                    // hold at the (smoothed) sustain level while gate
+                    // if gate turned off elsewhere, stage should have been set to Release
+                    v.sustain_level = sustain_smoothed[v.group];
+                    v.env = v.sustain_level * v.target_amp;
+                }
+                EnvStage::Release => {
+                    let end = match self.env_curve {
+                        EnvCurve::Linear => {
+                            v.env -= v.release_inc;
+                            0.0
+                        }
+                        EnvCurve::Exponential => {
+                            v.env -= v.env * v.release_rate;
+                            v.target_amp * EXP_ENV_THRESHOLD
+                        }
+                    };
+                    if v.env <= end {
+                        v.env = 0.0;
+                        v.stage = EnvStage::Idle;
+                        v.gate = false;
+                        // a stolen voice has finished fading out
+                        if let Some(p) = v.pending.take() {
+                            v.start_pending(p);
+                        }
+                    }
+                }
+            }
+            if v.stage == prev_stage {
+                v.stage_samples = v.stage_samples.saturating_add(1);
+            } else {
+                v.stage_samples = 0;
+            }
+
+            // glide towards the target frequency
+            if v.freq != v.target_freq {
+                match self.glide_curve {
+                    GlideCurve::Linear => {
+                        if (v.target_freq - v.freq).abs() <= v.glide_inc {
+                            v.freq = v.target_freq;
+                        } else if v.freq < v.target_freq {
+                            v.freq += v.glide_inc;
+                        } else {
+                            v.freq -= v.glide_inc;
+                        }
+                    }
+                    GlideCurve::Exponential => {
+                        let next = v.freq * v.glide_ratio;
+                        // stop on reaching or passing the target
+                        v.freq = if (next - v.target_freq) * (v.freq - v.target_freq) <= 0.0 {
+                            v.target_freq
+                        } else {
+                            next
+                        };
+                    }
+                }
+            }
+
+            // advance phase, which wraps around by itself
+            let phase_inc = if v.freq > 0.0 {
+                let bend = bend_ratio[v.channel as usize] * self.tune_ratio;
+                v.freq * vibrato * bend * self.phase_scale
+            } else {
+                0.0
+            };
+            // FM: the modulator swings osc 1's frequency by up to `fm_index` times its own
+            // frequency, so the increment can go negative, wrapping the phase backwards
+            let fm = if self.fm_index > 0.0 {
+                v.phase_fm = v
+                    .phase_fm
+                    .wrapping_add((phase_inc * self.fm_ratio) as i64 as u32);
+                let modulator = Sine.sample(v.phase_fm as f32 / PHASE_ONE, 0.0);
+                let depth = self.fm_index * self.fm_ratio * v.fm_env;
+                v.fm_env = flush_denormal(v.fm_env - v.fm_env * self.fm_env_rate);
+                1.0 + depth * modulator
+            } else {
+                1.0
+            };
+            let inc = (phase_inc * self.osc1_ratio * fm) as i64 as u32 as i32;
+            let wrapped;
+            (v.phase, wrapped) = v.phase.overflowing_add_signed(inc);
+            // Only wrapping forwards starts a new cycle, for hard sync. Running backwards
+            // past zero isn't one.
+            let wrapped = wrapped && inc > 0;
+            v.phase_sub = v
+                .phase_sub
+                .wrapping_add((phase_inc * self.osc1_ratio * 0.5) as u32);
+            if self.osc2_on {
+                v.phase2 = if self.osc_sync && wrapped {
+                    // hard sync: restart osc 2 in step with osc 1's wrap
+                    (v.phase as f32 * self.osc2_ratio / self.osc1_ratio) as u32
+                } else {
+                    v.phase2.wrapping_add((phase_inc * self.osc2_ratio) as u32)
+                };
+            }
+
+            if v.env > 0.0 {
+                let phase = v.phase as f32 / PHASE_ONE;
+                let dt = phase_inc / PHASE_ONE;
+                let plucked = matches!(v.osc, Osc::PluckedString);
+                let mut sample = if plucked {
+                    // one string per voice, so the second oscillator doesn't apply
+                    v.string.sample(dt * self.osc1_ratio)
+                } else {
+                    v.osc.sample(phase, dt * self.osc1_ratio)
+                };
+                if self.osc2_on && !plucked {
+                    let phase2 = v.phase2 as f32 / PHASE_ONE;
+                    let mut osc2 = v.osc.sample(phase2, dt * self.osc2_ratio);
+                    if self.ring_mod {
+                        osc2 *= sample;
+                    }
+                    sample = sample * (1.0 - self.osc_mix) + osc2 * self.osc_mix;
+                }
+                if self.noise_mix > 0.0 {
+                    let noise = white_noise(&mut v.rng);
+                    sample = sample * (1.0 - self.noise_mix) + noise * self.noise_mix;
+                }
+                if self.sub_level > 0.0 {
+                    let sub = if v.phase_sub < 0x8000_0000 { 1.0 } else { -1.0 };
+                    sample += sub * self.sub_level;
+                }
+
+                // Per-voice cutoff: sum all modulation sources, then clamp once
+                let filter_env = if v.target_amp > 0.0 {
+                    v.env / v.target_amp
+                } else {
+                    0.0
+                };
+                let keytrack_semitones = v.note as f32 - FILTER_KEYTRACK_REF_NOTE as f32;
+                let modulation = self.filter_env_amount * filter_env
+                    + self.filter_keytrack * keytrack_semitones / 127.0
+                    + self.vel_to_cutoff * v.velocity
+                    + AFTERTOUCH_CUTOFF_DEPTH * (self.channel_pressure + v.aftertouch);
+
+                // The voice's cutoff follows the modulated cutoff smoothly, so
+                // modulation that steps between buffers (pressure, CC sweeps)
+                // doesn't zipper
+                let patch = &patches[v.group];
+                let target = (patch.filter_cutoff + modulation).clamp(0.0, 1.0);
+                v.cutoff += (target - v.cutoff) * self.cutoff_rate;
+                if (target - v.cutoff).abs() < CUTOFF_SETTLED {
+                    v.cutoff = target;
+                }
+                let cutoff = v.cutoff;
+                let settled = cutoff == patch.filter_cutoff;
+
+                // Apply resonant state-variable filter (2-pole). The damping only
+                // depends on the resonance, and voices settled on the patch's
+                // cutoff use the cached frequency coefficient.
+                let (lowpass, highpass, bandpass) = match self.filter_model {
+                    FilterModel::Chamberlain => {
+                        let f = if settled {
+                            patch.filter_f
+                        } else {
+                            svf_f(cutoff_hz(cutoff))
+                        };
+                        let q_clamped = patch.filter_q;
+
+                        let lowpass = v.filter_buf1 + f * v.filter_buf0;
+                        let highpass = sample - lowpass - q_clamped * v.filter_buf0;
+                        let bandpass = f * highpass + v.filter_buf0;
+
+                        v.filter_buf0 = flush_denormal(bandpass);
+                        v.filter_buf1 = flush_denormal(lowpass);
+                        (lowpass, highpass, bandpass)
+                    }
+                    FilterModel::Tpt => {
+                        // Cytomic's trapezoidal SVF. The two buffers hold the
+                        // integrators' states.
+                        let g = if settled {
+                            patch.tpt_g
+                        } else {
+                            tpt_g(cutoff_hz(cutoff))
+                        };
+                        let k = patch.tpt_k;
+                        let a1 = 1.0 / (1.0 + g * (g + k));
+                        let a2 = g * a1;
+                        let a3 = g * a2;
+
+                        let v3 = sample - v.filter_buf1;
+                        let bandpass = a1 * v.filter_buf0 + a2 * v3;
+                        let lowpass = v.filter_buf1 + a2 * v.filter_buf0 + a3 * v3;
+                        let highpass = sample - k * bandpass - lowpass;
+
+                        v.filter_buf0 = flush_denormal(2.0 * bandpass - v.filter_buf0);
+                        v.filter_buf1 = flush_denormal(2.0 * lowpass - v.filter_buf1);
+                        (lowpass, highpass, bandpass)
+                    }
+                };
+
+                let filtered = match patch.filter_type {
+                    FilterType::LowPass => lowpass,
+                    FilterType::HighPass => highpass,
+                    FilterType::BandPass => bandpass,
+                    FilterType::Notch => lowpass + highpass,
+                };
+                mix_l += filtered * v.env * v.pan_l;
+                mix_r += filtered * v.env * v.pan_r;
+            }
+        }
+        (mix_l, mix_r, n_sounding)
+    }
+}
+
+/// The voices as they were before a Program Change, faded out over
+/// `PRESET_XFADE_FRAMES` while the voices with the new preset fade in
+#[cfg(feature = "preset-crossfade")]
+struct Crossfade {
+    voices: [Voice; N_VOICES],
+    active: heapless::Vec<usize, N_VOICES>,
+    patches: [Patch; N_PATCHES],
+    // While a crossfade runs, the settings the old voices are rendered with, and the
+    // frames faded so far
+    fade: Option<(VoiceRender, usize)>,
+}
+
+#[cfg(feature = "preset-crossfade")]
+impl Crossfade {
+    fn new() -> Self {
+        Self {
+            voices: [Voice::new(); N_VOICES],
+            active: heapless::Vec::new(),
+            patches: [Patch::new(); N_PATCHES],
+            fade: None,
+        }
+    }
+}
+
+// Per-sample fraction of the remaining distance covered by a one-pole (RC) curve
+// with a time constant of `time_constant_samples`
+#[inline]
+fn rc_rate(time_constant_samples: f32) -> f32 {
+    1.0 - (-1.0 / time_constant_samples).exp()
+}
+
+// Oscillator phase increment per Hz, at `sample_rate`
+fn phase_scale(sample_rate: u32) -> f32 {
+    PHASE_ONE / sample_rate as f32
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum EnvStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// A note waiting for a stolen voice to fade out
+#[derive(Copy, Clone)]
+struct PendingNote {
+    note: u8,
+    channel: u8,
+    pan: f32,
+    freq: f32,
+    vel_amp: f32,
+    age: u32,
+    attack_s: f32,
+    decay_s: f32,
+    sustain_level: f32,
+    random_phase: bool,
+    group: usize,
+    osc: Osc,
+    glide_from: Option<f32>,
+    portamento_s: f32,
+}
+
+#[derive(Copy, Clone)]
+struct Voice {
+    note: u8,
+    channel: u8,
+    freq: f32,
+    target_freq: f32,
+    glide_inc: f32,   // Hz per sample, for linear glide
+    glide_ratio: f32, // frequency multiplier per sample, for exponential glide
+    target_amp: f32,
+    env: f32,
+    gate: bool,
+    // Oscillator phases as fixed point fractions of a cycle, so they wrap for free
+    phase: u32,
+    phase2: u32,
+    phase_sub: u32, // sub-oscillator, at half osc 1's rate
+    phase_fm: u32,  // FM modulator
+    fm_env: f32,    // FM modulator envelope, 1.0 at note on, decaying to 0.0
+    rng: u32,       // noise generator state, never zero
+    osc: Osc,       // shared by both oscillators
+    // Karplus-Strong string, played instead of the oscillators by the plucked string
+    // waveform. It's the bulk of the voice's size, see `PLUCK_LEN`.
+    string: PluckedString,
+    group: usize, // which patch the voice plays
+    age: u32,
+    // stereo gains, from the pan position
+    pan_l: f32,
+    pan_r: f32,
+    velocity: f32,   // 0.0 to 1.0, note-on velocity
+    aftertouch: f32, // 0.0 to 1.0, from polyphonic aftertouch (0xA0)
+    // ADSR fields
+    env_keytrack: f32, // 0.0 to 1.0, shortens the stage times of higher notes (MIDI CC 59)
+    vel_to_attack: f32, // 0.0 to 1.0, shortens the attack of harder notes (MIDI CC 90)
+    attack_scale: f32, // attack time multiplier for the note's velocity
+    stage: EnvStage,
+    stage_samples: u32, // samples spent in the current stage
+    attack_inc: f32,
+    decay_inc: f32,
+    sustain_level: f32,
+    release_inc: f32,
+    // per-sample approach rates for exponential curves
+    attack_rate: f32,
+    decay_rate: f32,
+    release_rate: f32,
+    pending: Option<PendingNote>,
+    // Filter state (simple 2-pole resonant low-pass), and the smoothed cutoff (0.0 to
+    // 1.0, as the patch's)
+    cutoff: f32,
+    filter_buf0: f32,
+    filter_buf1: f32,
+}
+
+impl Voice {
+    const fn new() -> Self {
+        Self {
+            note: 0,
+            channel: 0,
+            freq: 0.0,
+            target_freq: 0.0,
+            glide_inc: 0.0,
+            glide_ratio: 1.0,
+            target_amp: 0.0,
+            env: 0.0,
+            gate: false,
+            phase: 0,
+            phase2: 0,
+            phase_sub: 0,
+            phase_fm: 0,
+            fm_env: 0.0,
+            rng: 1,
+            osc: Osc::Sine(Sine),
+            string: PluckedString::new(),
+            group: 0,
+            age: 0,
+            pan_l: 1.0,
+            pan_r: 1.0,
+            velocity: 0.0,
+            aftertouch: 0.0,
+            env_keytrack: 0.0,
+            vel_to_attack: 0.0,
+            attack_scale: 1.0,
+            stage: EnvStage::Idle,
+            stage_samples: 0,
+            attack_inc: 0.0,
+            decay_inc: 0.0,
+            sustain_level: 1.0,
+            release_inc: 0.0,
+            attack_rate: 1.0,
+            decay_rate: 1.0,
+            release_rate: 1.0,
+            pending: None,
+            cutoff: DEFAULT_FILTER_CUTOFF,
+            filter_buf0: 0.0,
+            filter_buf1: 0.0,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn start_with_adsr(
+        &mut self,
+        note: u8,
+        freq: f32,
+        vel_amp: f32,
+        age: u32,
+        attack_s: f32,
+        decay_s: f32,
+        sustain_level: f32,
+        random_phase: bool,
+        retrigger: Retrigger,
+    ) {
+        self.note = note;
+        self.freq = freq;
+        self.target_freq = freq;
+        self.target_amp = vel_amp;
+        self.gate = true;
+        self.age = age;
+        self.velocity = vel_amp;
+        self.aftertouch = 0.0;
+        self.attack_scale = if self.vel_to_attack > 0.0 {
+            VEL_TO_ATTACK_RANGE.powf(self.vel_to_attack * (1.0 - 2.0 * vel_amp))
+        } else {
+            1.0
+        };
+        // seed the noise generator from the note and age so voices differ
+        self.rng = (age.wrapping_mul(0x9E37_79B9) ^ ((note as u32) << 24)) | 1;
+        match &mut self.osc {
+            Osc::Noise(noise) => noise.rng = self.rng.rotate_left(16) | 1,
+            Osc::PluckedString => self.pluck(),
+            _ => {}
+        }
+        // start from decorrelated phases, hashed from the same seed, so the voices of a
+        // chord don't all start in step. A voice that's still sounding keeps its phase,
+        // so retriggering it doesn't click.
+        if !self.active() {
+            if random_phase {
+                self.phase = self.rng;
+                self.phase2 = self.rng.wrapping_mul(0x85EB_CA6B);
+            } else {
+                self.phase = 0;
+                self.phase2 = 0;
+            }
+            self.phase_sub = self.phase >> 1;
+            self.phase_fm = self.phase;
+        }
+        self.fm_env = 1.0;
+        self.sustain_level = sustain_level;
+
+        // compute per-sample increments (simple linear ramps)
+        self.set_attack_time(attack_s);
+        self.set_decay_time(decay_s);
+
+        // release_inc will be computed at note-off based on current env
+        self.release_inc = 0.0;
+
+        // start envelope
+        self.stage = EnvStage::Attack;
+        self.stage_samples = 0;
+        match retrigger {
+            Retrigger::Partial => {
+                // keep current env to avoid hard clicks; if env is 0 start at tiny value
+                if self.env <= 0.0 {
+                    self.env = 0.0;
+                }
+            }
+            Retrigger::Full => self.env = 0.0,
+            Retrigger::Continue => {
+                // skip the attack peak if the voice is already above the new sustain level
+                if self.env > 0.0 && self.env >= self.sustain_level * self.target_amp {
+                    self.stage = EnvStage::Decay;
+                }
+            }
+        }
+    }
+
+    /// Assign the voice to a patch, ready for its next note
+    fn set_patch(&mut self, group: usize, patch: &Patch) {
+        self.group = group;
+        self.osc = patch.osc(self.rng.rotate_left(16));
+        self.cutoff = patch.filter_cutoff;
+    }
+
+    /// Assign the voice to a MIDI channel, with the given pan (-1.0 left to 1.0 right)
+    fn set_channel(&mut self, channel: u8, pan: f32) {
+        self.channel = channel;
+        self.set_pan(pan);
+    }
+
+    // Equal-power pan law, scaled so a centred voice has unity gain in both channels
+    fn set_pan(&mut self, pan: f32) {
+        let angle = (pan + 1.0) * core::f32::consts::FRAC_PI_4;
+        self.pan_l = core::f32::consts::SQRT_2 * angle.cos();
+        self.pan_r = core::f32::consts::SQRT_2 * angle.sin();
+    }
+
+    fn start_pending(&mut self, p: PendingNote) {
+        self.group = p.group;
+        self.osc = p.osc;
+        self.start_with_adsr(
+            p.note,
+            p.freq,
+            p.vel_amp,
+            p.age,
+            p.attack_s,
+            p.decay_s,
+            p.sustain_level,
+            p.random_phase,
+            // the stolen voice has already faded out, so this makes no difference
+            Retrigger::Full,
+        );
+        if let Some(from) = p.glide_from {
+            self.glide_from(from, p.portamento_s);
+        }
+        self.set_channel(p.channel, p.pan);
+    }
+
+    /// Quickly fade out the current note, then start `p` in its place
+    fn steal(&mut self, p: PendingNote) {
+        self.note_off(STEAL_FADE_S);
+        self.age = p.age;
+        self.pending = Some(p);
+    }
+
+    /// Release `note` if it's held on this voice from `channel`, or cancel it if it's
+    /// waiting for a stolen voice to fade out
+    fn release_note(&mut self, note: u8, channel: u8, release_s: f32) {
+        if self.note == note && self.channel == channel && self.gate {
+            self.note_off(release_s);
+        } else if self
+            .pending
+            .is_some_and(|p| p.note == note && p.channel == channel)
+        {
+            self.pending = None;
+        }
+    }
+
+    /// Start the current note at `from` Hz, gliding up or down to its pitch
+    fn glide_from(&mut self, from: f32, glide_s: f32) {
+        let to = self.target_freq;
+        self.freq = from;
+        self.glide_to(to, glide_s);
+    }
+
+    // Glide from the current frequency to `freq` over `glide_s`, with either glide curve
+    fn glide_to(&mut self, freq: f32, glide_s: f32) {
+        self.target_freq = freq;
+        let glide_samples = glide_s * (SAMPLE_RATE as f32);
+        // Exponential glide's step per sample. The direction comes from comparing the
+        // frequencies rather than the sign of the log, which is approximate, so the glide
+        // can't head away from its target.
+        let log_step = if self.freq > 0.0 {
+            (freq / self.freq).ln().abs() / glide_samples
+        } else {
+            0.0
+        };
+        let ratio = log_step.exp();
+        if glide_samples < 1.0 || ratio <= 1.0 {
+            self.freq = freq;
+            self.glide_inc = 0.0;
+            self.glide_ratio = 1.0;
+        } else {
+            self.glide_inc = (freq - self.freq).abs() / glide_samples;
+            self.glide_ratio = if freq > self.freq { ratio } else { 1.0 / ratio };
+        }
+    }
+
+    // Envelope time multiplier for the voice's note. At full keytracking the times
+    // halve for every octave above middle C (and double below it).
+    fn env_time_scale(&self) -> f32 {
+        if self.env_keytrack > 0.0 {
+            let octaves = (self.note as f32 - ENV_KEYTRACK_REF_NOTE as f32) / 12.0;
+            2f32.powf(-self.env_keytrack * octaves)
+        } else {
+            1.0
+        }
+    }
+
+    // Stage length in samples, for a stage time in seconds
+    fn stage_samples(&self, time_s: f32) -> f32 {
+        (time_s * self.env_time_scale() * (SAMPLE_RATE as f32)).max(1.0)
+    }
+
+    // The attack and decay increments only depend on the levels the stages run
+    // between, so they can be changed partway through a stage without a jump in level.
+    // Exponential curves use the stage times as time constants.
+    fn set_attack_time(&mut self, attack_s: f32) {
+        let attack_samples = self.stage_samples(attack_s * self.attack_scale);
+        self.attack_inc = self.target_amp / attack_samples;
+        self.attack_rate = rc_rate(attack_samples);
+    }
+
+    fn set_decay_time(&mut self, decay_s: f32) {
+        let decay_samples = self.stage_samples(decay_s);
+        let sustain_target = self.sustain_level * self.target_amp;
+        self.decay_inc = (self.target_amp - sustain_target) / decay_samples;
+        self.decay_rate = rc_rate(decay_samples);
+    }
+
+    // The release runs from the current level, so when the time changes partway through
+    // the release, the rest of it takes the new time
+    fn set_release_time(&mut self, release_s: f32) {
+        let release_samples = self.stage_samples(release_s);
+        self.release_inc = self.env / release_samples;
+        self.release_rate = rc_rate(release_samples);
+    }
+
+    // Restart the string with noise, seeded from the voice's noise generator
+    fn pluck(&mut self) {
+        let mut seed = self.rng.rotate_left(8);
+        self.string.pluck(&mut seed);
+    }
+
+    fn note_off(&mut self, release_s: f32) {
+        self.gate = false;
+        // compute release increment to bring env to 0 over release_s seconds
+        self.set_release_time(release_s);
+        self.stage = EnvStage::Release;
+        self.stage_samples = 0;
+    }
+
+    fn active(&self) -> bool {
+        self.stage != EnvStage::Idle || self.env > 1e-6
+    }
+
+    /// Whether this voice is holding `note` from `channel` on patch `group`
+    fn plays(&self, note: u8, channel: u8, group: usize) -> bool {
+        self.gate && self.note == note && self.channel == channel && self.group == group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::testing::*;
+
+    // Every 64th frame of `golden_render`, as rendered when it was written. The patch
+    // is centred, so both channels match it.
+    const GOLDEN_STEP: usize = 64;
+    const GOLDEN: [f32; 48] = [
+        0.0, -661.0, 4977.0, 4635.0, -5101.0, -6769.0, -1783.0, -829.0, 4223.0, 3529.0, -5807.0,
+        6040.0, -3125.0, -2210.0, 9324.0, -12591.0, 9673.0, -2707.0, -4228.0, 6884.0, -4339.0,
+        -1218.0, 5379.0, -5000.0, 748.0, 3715.0, -4910.0, 2127.0, 2113.0, -4304.0, 2888.0, 681.0,
+        -3372.0, 3259.0, -457.0, -2729.0, 3610.0, -1485.0, -1873.0, 3647.0, -2372.0, -876.0,
+        3374.0, -3046.0, 177.0, 2822.0, -3452.0, 1199.0,
+    ];
+    // Allowed difference, in 16-bit steps: float rounding that varies with the build,
+    // the extra resolution of 32-bit output and the rescaling for mono output, but
+    // nothing audible
+    const GOLDEN_TOLERANCE: f32 = 3.0;
+
+    // A note through the power-on patch, with a resonant filter sweep while it's held,
+    // then into its release. Note ages, and so the noise seeds and oscillator phases,
+    // start from zero in a new synth, so this renders the same every time.
+    #[test]
+    fn golden_render() {
+        let mut t = TestSynth::new();
+        t.cc(27, 64);
+        t.note_on(60, 100);
+        let mut frames = Vec::new();
+        for i in 0..16 {
+            t.cc(26, 20 + i * 6);
+            frames.extend(t.render(1));
+        }
+        t.note_off(60);
+        frames.extend(t.render(8));
+
+        for (i, (&(l, r), expected)) in frames.iter().step_by(GOLDEN_STEP).zip(GOLDEN).enumerate() {
+            let frame = i * GOLDEN_STEP;
+            #[cfg(feature = "mono-out")]
+            let expected = 2.0 * expected * MONO_SUM_GAIN;
+            assert!(
+                (l - expected).abs() <= GOLDEN_TOLERANCE
+                    && (r - expected).abs() <= GOLDEN_TOLERANCE,
+                "frame {frame}: got ({l}, {r}), expected {expected}"
+            );
+        }
+    }
+
+    // With full keytracking the LFO rate doubles an octave up, and halves an octave down
+    #[test]
+    fn lfo_rate_rises_with_note_under_keytrack() {
+        let mut t = TestSynth::new();
+        t.cc(28, 127);
+        let mut rates = Vec::new();
+        for note in [48, 60, 72] {
+            t.note_on(note, 100);
+            t.render(1);
+            rates.push(t.synth.lfo_rate_hz());
+        }
+        assert!(rates[0] < rates[1] && rates[1] < rates[2], "{rates:?}");
+        assert!((rates[1] / rates[0] - 2.0).abs() < 1e-3, "{rates:?}");
+        assert!((rates[2] / rates[1] - 2.0).abs() < 1e-3, "{rates:?}");
+
+        // and without keytracking it stays put
+        t.cc(28, 0);
+        t.render(1);
+        assert_eq!(t.synth.lfo_rate_hz(), rates[1]);
+    }
+
+    // The filter envelope, keytracking and velocity offsets all add to the patch's
+    // cutoff, which is clamped once they're summed
+    #[test]
+    fn cutoff_sources_combine() {
+        let mut t = TestSynth::new();
+        t.cc(26, 0);
+        t.synth.filter_env_amount = 0.25;
+        t.synth.filter_keytrack = 0.5;
+        t.synth.vel_to_cutoff = 0.2;
+        t.note_on(72, 127);
+        // past the attack and decay, so the envelope is holding at the sustain level
+        t.render(40);
+
+        let v = &t.synth.voices[t.synth.active[0]];
+        let expected = 0.25 * v.env / v.target_amp + 0.5 * 12.0 / 127.0 + 0.2 * v.velocity;
+        assert!(
+            (v.cutoff - expected).abs() < 1e-4,
+            "{} vs {expected}",
+            v.cutoff
+        );
+
+        // with the patch cutoff wide open the sum is clamped
+        t.cc(26, 127);
+        t.render(10);
+        let v = &t.synth.voices[t.synth.active[0]];
+        assert_eq!(v.cutoff, 1.0);
+    }
+
+    // Muting fades the output to silence, but the voices keep playing underneath
+    #[test]
+    fn mute_silences_output_but_voices_run() {
+        let mut t = TestSynth::new();
+        t.note_on(60, 100);
+        t.render(4);
+        t.cc(31, 127);
+        // past the fade out
+        t.render(4);
+
+        let i = t.synth.active[0];
+        let phase = t.synth.voices[i].phase;
+        let frames = t.render(4);
+        assert!(frames.iter().all(|&f| f == (0.0, 0.0)));
+        assert_ne!(t.synth.voices[i].phase, phase);
+
+        t.cc(31, 0);
+        let frames = t.render(4);
+        assert!(frames.iter().any(|&(l, _)| l != 0.0));
+    }
+
+    // Global bend moves every voice, per-note bend only the voices on the bend's channel
+    #[test]
+    fn bend_scopes() {
+        let mut t = TestSynth::new();
+        t.send(0x90, 60, 100);
+        t.send(0x91, 64, 100);
+        t.render(1);
+        let bend = |t: &TestSynth, note: u8| {
+            let v = t.synth.voices.iter().find(|v| v.gate && v.note == note);
+            t.synth.bend_ratio[v.unwrap().channel as usize]
+        };
+
+        // full bend up on channel 2
+        t.send(0xE1, 0x7F, 0x7F);
+        t.render(1);
+        assert!(bend(&t, 60) > 1.0);
+        assert_eq!(bend(&t, 60), bend(&t, 64));
+
+        t.cc(33, 127);
+        t.send(0xE1, 0x7F, 0x7F);
+        t.render(1);
+        assert_eq!(bend(&t, 60), 1.0);
+        assert!(bend(&t, 64) > 1.0);
+    }
+
+    // The calibration sines peak at their stated levels, and stay there
+    #[test]
+    fn test_sine_peak_level() {
+        let mut t = TestSynth::new();
+        t.cc(34, 32);
+        // past the fade in
+        t.render(8);
+        let full_scale = peak(&t.render(8));
+        assert!((32766.0..=32767.0).contains(&full_scale), "{full_scale}");
+        // a second later
+        t.render(375);
+        let full_scale = peak(&t.render(8));
+        assert!((32766.0..=32767.0).contains(&full_scale), "{full_scale}");
+
+        t.cc(34, 64);
+        // fading out the old signal, then in the new one
+        t.render(16);
+        let tone = peak(&t.render(8));
+        assert!((tone - 3277.0).abs() <= 1.0, "{tone}");
+    }
+
+    // Each stage's sample count starts from zero and counts up until the next stage
+    #[test]
+    fn stage_samples_reset_and_count() {
+        let mut t = TestSynth::new();
+        t.note_on(60, 100);
+        t.render(1);
+        let i = t.synth.active[0];
+        assert!(t.synth.voice_stage_info(i) == (EnvStage::Attack, CHUNK_FRAMES as u32));
+
+        // the 5 ms attack ends partway through the second chunk
+        t.render(1);
+        let (stage, samples) = t.synth.voice_stage_info(i);
+        assert!(stage == EnvStage::Decay);
+        assert!(samples > 0 && samples < CHUNK_FRAMES as u32, "{samples}");
+
+        t.note_off(60);
+        t.render(1);
+        assert!(t.synth.voice_stage_info(i) == (EnvStage::Release, CHUNK_FRAMES as u32));
+    }
+
+    // An exponential stage covers 63% (1 - 1/e) of the way to its target in one time
+    // constant, which is the stage time
+    #[test]
+    fn exponential_attack_time_constant() {
+        let mut t = TestSynth::new();
+        t.cc(38, 127);
+        t.note_on(60, 100);
+        let attack_samples = (DEFAULT_ATTACK_TIME_S * SAMPLE_RATE as f32) as usize;
+        t.render_frames(attack_samples);
+
+        let v = &t.synth.voices[t.synth.active[0]];
+        let level = v.env / v.target_amp;
+        assert!((level - (1.0 - (-1.0f32).exp())).abs() < 1e-3, "{level}");
+    }
+
+    // Stealing the only voice fades it out before the new note starts, so the sine
+    // doesn't jump. A hard cut would jump by as much as the sine's level, many times
+    // the largest step between samples of the held note.
+    #[test]
+    fn steal_has_no_jump() {
+        let max_delta = |frames: &[(f32, f32)]| {
+            frames
+                .windows(2)
+                .map(|w| (w[1].0 - w[0].0).abs())
+                .fold(0.0, f32::max)
+        };
+        let mut t = TestSynth::new();
+        t.synth.polyphony = 1;
+        t.cc(21, 0);
+        t.cc(24, 127);
+        t.note_on(48, 127);
+        t.render(40);
+        let held = t.render(4);
+
+        t.note_on(52, 127);
+        let mut frames = held[held.len() - 1..].to_vec();
+        frames.extend(t.render(4));
+        assert_eq!(t.synth.voices[0].note, 52);
+
+        let (steady, steal) = (max_delta(&held), max_delta(&frames));
+        assert!(
+            steal < 2.0 * steady,
+            "{steal} across the steal, {steady} held"
+        );
+    }
+
+    // Once a note has died away, the idle voices are skipped, and the output is exactly
+    // what a synth that never played anything gives
+    #[test]
+    fn idle_output_matches_fresh_synth() {
+        let mut played = TestSynth::new();
+        played.note_on(60, 100);
+        played.render(8);
+        played.note_off(60);
+        // through the 500 ms release, and long enough after for the effects to settle
+        played.render(800);
+        assert!(played.synth.active.is_empty());
+        assert!(
+            played
+                .synth
+                .voices
+                .iter()
+                .all(|v| v.stage == EnvStage::Idle)
+        );
+
+        let mut fresh = TestSynth::new();
+        fresh.render(808);
+        assert_eq!(played.render(8), fresh.render(8));
+    }
+
+    // A4 (note 69) cycles 440 times a second at either common sample rate
+    #[test]
+    fn cycle_length_at_sample_rates() {
+        let freq = Tuning::STANDARD.note_to_freq(69);
+        for sample_rate in [44_100, 48_000] {
+            let inc = (freq * phase_scale(sample_rate)) as u32;
+            let cycle_samples = PHASE_ONE / inc as f32;
+            let expected = sample_rate as f32 / freq;
+            assert!(
+                (cycle_samples - expected).abs() < 1e-3,
+                "{sample_rate} Hz: {cycle_samples}"
+            );
+
+            // from half a cycle in, so the last wrap isn't right on the end of the second
+            let (mut phase, mut cycles) = (0x8000_0000u32, 0);
+            for _ in 0..sample_rate {
+                let wrapped;
+                (phase, wrapped) = phase.overflowing_add(inc);
+                cycles += wrapped as u32;
+            }
+            assert_eq!(cycles, 440, "{sample_rate} Hz");
+        }
+    }
+
+    // A Program Change while notes sound crossfades from the old voices to the new
+    #[cfg(feature = "preset-crossfade")]
+    #[test]
+    fn program_change_crossfades() {
+        let mut t = TestSynth::new();
+        t.note_on(60, 100);
+        t.render(4);
+        t.send(0xC0, 1, 0);
+        t.render_frames(1);
+        assert!(t.synth.crossfade.fade.is_some());
+        assert!(t.synth.crossfade.voices[t.synth.active[0]].gate);
+        t.render_frames(PRESET_XFADE_FRAMES);
+        assert!(t.synth.crossfade.fade.is_none());
+    }
+
+    // With an RPN selected, CC 38 is the Data Entry LSB, otherwise the envelope curve
+    #[test]
+    fn data_entry_lsb() {
+        let mut t = TestSynth::new();
+        // fine tune, 8192 + 4096: half way up
+        t.cc(101, 0);
+        t.cc(100, 1);
+        t.cc(6, 96);
+        t.cc(38, 0);
+        t.render(1);
+        assert_eq!(t.synth.fine_tune_cents, 50.0);
+        // the LSB refines the MSB: 8192 - 1 is just below centre
+        t.cc(6, 63);
+        t.cc(38, 127);
+        t.render(1);
+        assert!(t.synth.fine_tune_cents < 0.0 && t.synth.fine_tune_cents > -0.1);
+        assert!(t.synth.env_curve == EnvCurve::Linear);
+
+        // bend range in semitones and cents
+        t.cc(100, 0);
+        t.cc(6, 2);
+        t.cc(38, 50);
+        t.render(1);
+        assert_eq!(t.synth.bend_range_semitones, 2.5);
+
+        // deselected, CC 38 sets the envelope curve again
+        t.cc(101, 127);
+        t.cc(100, 127);
+        t.cc(38, 127);
+        t.render(1);
+        assert!(t.synth.env_curve == EnvCurve::Exponential);
+        assert_eq!(t.synth.bend_range_semitones, 2.5);
+    }
+
+    // Switching a held note to the plucked string plucks it, rather than leaving it
+    // silent until the next note
+    #[test]
+    fn switching_to_string_plucks_held_notes() {
+        let mut t = TestSynth::new();
+        t.cc(24, 127);
+        t.note_on(60, 100);
+        t.render(8);
+        t.cc(21, 127);
+        // once the filter has stopped ringing from the sine
+        t.render(2);
+        let level = peak(&t.render(4));
+        assert!(level > 1000.0, "{level}");
+    }
+
+    // With every voice held, each note of a chord steals a different voice, picked by
+    // the stealing policy. The held notes go from high to low, so the oldest aren't the
+    // lowest, and three of them are played softly to be the quietest.
+    #[test]
+    fn steal_policies() {
+        let notes: Vec<u8> = (40..40 + N_VOICES as u8).rev().collect();
+        let quiet = [40, 42, 43];
+        let chord = [90, 91, 92];
+        let oldest = [notes[0], notes[1], notes[2]];
+        for (cc_val, victims) in [
+            (0, Some(oldest)),
+            (32, Some(quiet)),
+            (64, Some([40, 41, 42])),
+            (96, None),
+        ] {
+            let mut t = TestSynth::new();
+            t.cc(89, cc_val);
+            t.cc(24, 127);
+            for &note in &notes {
+                t.note_on(note, if quiet.contains(&note) { 20 } else { 127 });
+            }
+            t.render(8);
+            for note in chord {
+                t.note_on(note, 100);
+            }
+            // past the stolen voices' fade out
+            t.render(2);
+
+            let mut held: Vec<u8> = t
+                .synth
+                .voices
+                .iter()
+                .filter(|v| v.gate)
+                .map(|v| v.note)
+                .collect();
+            held.sort();
+            let mut expected: Vec<u8> = match victims {
+                Some(victims) => notes
+                    .iter()
+                    .copied()
+                    .filter(|n| !victims.contains(n))
+                    .chain(chord)
+                    .collect(),
+                None => notes.clone(),
+            };
+            expected.sort();
+            assert_eq!(held, expected, "policy {}", cc_val / 32);
+        }
+    }
+
+    // Polyphonic aftertouch only reaches the key pressed on its own channel, not the same
+    // note held on another one
+    #[test]
+    fn aftertouch_matches_channel() {
+        let mut t = TestSynth::new();
+        t.send(0x90, 60, 100);
+        t.send(0x91, 60, 100);
+        t.render(1);
+        t.send(0xA1, 60, 127);
+        t.render(1);
+        let pressure = |channel| {
+            let v = t
+                .synth
+                .voices
+                .iter()
+                .find(|v| v.gate && v.channel == channel);
+            v.unwrap().aftertouch
+        };
+        assert_eq!(pressure(0), 0.0);
+        assert_eq!(pressure(1), 1.0);
+    }
+
+    // A repeated note on for a held key retriggers its voice, so a single note off
+    // still lets it go
+    #[test]
+    fn duplicate_note_on_one_note_off() {
+        let mut t = TestSynth::new();
+        t.note_on(60, 100);
+        t.render(4);
+        t.note_on(60, 100);
+        t.render(4);
+        assert_eq!(t.synth.voices.iter().filter(|v| v.gate).count(), 1);
+        t.note_off(60);
+        // through the 500 ms release
+        t.render(250);
+        assert!(t.synth.voices.iter().all(|v| !v.active()));
+    }
+
+    // A note off sent as 0x80 and as a velocity 0 note on leave the voices the same, both
+    // for a plain release and for a key let go under the sustain pedal
+    #[test]
+    fn note_off_encodings_match() {
+        let run = |status: u8| {
+            let mut t = TestSynth::new();
+            t.note_on(60, 100);
+            t.note_on(64, 100);
+            t.render(4);
+            t.send(status, 60, 0);
+            t.cc(64, 127);
+            t.send(status, 64, 0);
+            let frames = t.render(8);
+            let voices: Vec<_> = t
+                .synth
+                .voices
+                .iter()
+                .map(|v| {
+                    (
+                        v.note,
+                        v.channel,
+                        v.gate,
+                        v.stage as u8,
+                        v.stage_samples,
+                        v.env,
+                    )
+                })
+                .collect();
+            (frames, voices)
+        };
+        assert_eq!(run(0x80), run(0x90));
+    }
+
+    // A one-octave glide takes the portamento time. Halfway through, exponential glide is
+    // half way there in pitch, at the sixth semitone, and linear glide half way in Hz.
+    #[test]
+    fn glide_time_and_midpoint() {
+        for (cc_val, mid_semitones) in [(127, 6.0), (0, 12.0 * 1.5f32.log2())] {
+            let mut t = TestSynth::new();
+            t.cc(63, cc_val);
+            t.cc(65, 127);
+            t.cc(5, 32);
+            t.note_on(57, 100);
+            t.render(1);
+            t.note_off(57);
+            t.note_on(69, 100);
+            let glide_samples = (t.synth.portamento_time_s * SAMPLE_RATE as f32) as usize;
+            let margin = glide_samples / 100;
+            let freq = |t: &TestSynth| {
+                let v = t.synth.voices.iter().find(|v| v.gate && v.note == 69);
+                let v = v.unwrap();
+                (v.freq, v.target_freq)
+            };
+
+            t.render_frames(glide_samples / 2);
+            let (mid, target) = freq(&t);
+            let semitones = 12.0 + 12.0 * (mid / target).log2();
+            assert!(
+                (semitones - mid_semitones).abs() < 0.05,
+                "curve {cc_val}: {semitones} semitones at halfway"
+            );
+            t.render_frames(glide_samples / 2 - margin);
+            let (f, target) = freq(&t);
+            assert!(f != target, "curve {cc_val}: glide ended early");
+            t.render_frames(2 * margin);
+            let (f, target) = freq(&t);
+            assert!(f == target, "curve {cc_val}: glide still going");
+        }
+    }
+
+    // The fixed point phase runs within a cent of the note's exact frequency, at the low
+    // and high ends of the piano
+    #[test]
+    fn phase_accumulator_within_a_cent() {
+        for note in [21, 108] {
+            let mut t = TestSynth::new();
+            t.note_on(note, 100);
+            t.render_frames(1);
+            let voice = t.synth.voices.iter().position(|v| v.gate).unwrap();
+            // a frame at a time, so no cycle goes by unseen
+            let (mut phase, mut total) = (t.synth.voices[voice].phase, 0u64);
+            for _ in 0..SAMPLE_RATE {
+                t.render_frames(1);
+                let next = t.synth.voices[voice].phase;
+                total += next.wrapping_sub(phase) as u64;
+                phase = next;
+            }
+            let freq = total as f64 / PHASE_ONE as f64;
+            let expected = Tuning::STANDARD.note_to_freq(note) as f64;
+            let cents = 1200.0 * (freq / expected).log2();
+            assert!(cents.abs() < 1.0, "note {note}: {cents} cents out");
+        }
+    }
+
+    // The mix is scaled by the number of sounding voices, so a chord of ten notes (or as
+    // many as there are voices) comes out at about the level of a single note, rather
+    // than a single note being ten times quieter than the chord
+    #[test]
+    fn chord_level_matches_single_note() {
+        let rms = |n_notes: usize| {
+            let mut t = TestSynth::new();
+            for i in 0..n_notes {
+                t.note_on(48 + 5 * i as u8, 100);
+            }
+            t.render(16);
+            let frames = t.render(64);
+            let sum: f32 = frames.iter().map(|&(l, r)| l * l + r * r).sum();
+            (sum / (2 * frames.len()) as f32).sqrt()
+        };
+        let single = rms(1);
+        let chord = rms(N_VOICES.min(10));
+        assert!(
+            (0.7..=1.4).contains(&(chord / single)),
+            "single note {single}, chord {chord}"
+        );
+    }
+
+    // Jumping the volume (CC 7) or sustain level (CC 24) all the way down and back up
+    // ramps the level, with no step in the output bigger than a held note's own
+    #[test]
+    fn level_jumps_ramp() {
+        let max_step = |frames: &[(f32, f32)]| {
+            frames
+                .windows(2)
+                .map(|w| (w[1].0 - w[0].0).abs().max((w[1].1 - w[0].1).abs()))
+                .fold(0.0, f32::max)
+        };
+        for cc in [7, 24] {
+            let mut t = TestSynth::new();
+            t.cc(24, 127);
+            t.note_on(48, 127);
+            // into the sustain
+            t.render(200);
+            let held = max_step(&t.render(8));
+            let mut frames = Vec::new();
+            for value in [0, 127, 0, 127] {
+                t.cc(cc, value);
+                frames.extend(t.render(4));
+            }
+            let step = max_step(&frames);
+            assert!(step < 1.5 * held, "CC {cc}: step {step}, held note {held}");
+        }
+    }
+
+    // A note panned hard left (CC 10 = 0) leaves the right channel silent
+    #[cfg(not(feature = "mono-out"))]
+    #[test]
+    fn hard_left_pan() {
+        let mut t = TestSynth::new();
+        t.cc(10, 0);
+        t.note_on(60, 100);
+        let frames = t.render(16);
+        let left = frames
+            .iter()
+            .fold(0.0f32, |peak, &(l, _)| peak.max(l.abs()));
+        let right = frames
+            .iter()
+            .fold(0.0f32, |peak, &(_, r)| peak.max(r.abs()));
+        assert!(left > 1000.0, "left peak {left}");
+        assert!(right <= 1.0, "right peak {right}");
+    }
+
+    // Offset binary puts silence at mid-scale, 0x8000 in each 16-bit sample, and full
+    // scale either side of it
+    #[test]
+    fn offset_binary_mid_scale() {
+        let write = |sample: f32| {
+            let mut frame = [0u32; WORDS_PER_FRAME];
+            write_frame(&mut frame, sample, sample, SampleFormat::OffsetBinary);
+            frame
+        };
+        #[cfg(not(feature = "i2s-32bit"))]
+        {
+            assert_eq!(write(0.0), [0x8000_8000]);
+            assert_eq!(write(32767.0), [0xFFFF_FFFF]);
+            assert_eq!(write(-32767.0), [0x0001_0001]);
+        }
+        #[cfg(feature = "i2s-32bit")]
+        {
+            assert_eq!(write(0.0), [0x8000_0000; 2]);
+            assert_eq!(write(32767.0), [0xFFFF_0000; 2]);
+            assert_eq!(write(-32767.0), [0x0001_0000; 2]);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::synth::{DUMP_REQUESTED, LOAD_PARAMS, PARAMS_LEN, Params};
+use core::sync::atomic::Ordering;
+use defmt::{debug, info};
+
+// 0x7D is the manufacturer ID set aside for non-commercial use. It's followed by a
+// model ID, so dumps meant for other 0x7D devices are ignored.
+const MANUFACTURER_ID: u8 = 0x7D;
+const MODEL_ID: u8 = 0x4F; // 'O'
+
+const CMD_DUMP_REQUEST: u8 = 0x01;
+const CMD_DUMP: u8 = 0x02;
+
+// SysEx data bytes are 7 bits, so each parameter byte is sent as two nibbles, followed
+// by a checksum
+const DUMP_DATA_LEN: usize = 2 * PARAMS_LEN + 1;
+// Length of a complete parameter dump, from the 0xF0 to the 0xF7
+const DUMP_LEN: usize = 5 + DUMP_DATA_LEN;
+
+/// A SysEx message for the synth. It's only held for as long as it takes to act on it, so
+/// the size of a dump doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum SysexCommand {
+    /// Send the current parameters back as a dump
+    DumpRequest,
+    /// Replace all the sound parameters
+    Dump(Params),
+}
+
+/// Collects a SysEx message from a stream of MIDI bytes, as they arrive in USB MIDI
+/// packets or on the serial input. Messages too long to be for the synth are dropped.
+pub struct SysexAssembler {
+    buf: heapless::Vec<u8, DUMP_LEN>,
+    receiving: bool,
+}
+
+impl SysexAssembler {
+    pub const fn new() -> Self {
+        Self {
+            buf: heapless::Vec::new(),
+            receiving: false,
+        }
+    }
+
+    /// Add a byte, returning the whole message (including 0xF0 and 0xF7) once the
+    /// 0xF7 arrives
+    pub fn feed(&mut self, byte: u8) -> Option<&[u8]> {
+        match byte {
+            0xF0 => {
+                self.buf.clear();
+                self.receiving = self.buf.push(byte).is_ok();
+                None
+            }
+            0xF7 if self.receiving => {
+                self.receiving = false;
+                if self.buf.push(byte).is_ok() {
+                    Some(self.buf.as_slice())
+                } else {
+                    None
+                }
+            }
+            // Real-time bytes can be interleaved with SysEx data
+            0xF8..=0xFF => None,
+            // Any other status byte ends the message early
+            0x80..=0xF7 => {
+                self.receiving = false;
+                None
+            }
+            _ => {
+                if self.receiving && self.buf.push(byte).is_err() {
+                    self.receiving = false;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Act on a complete SysEx message from the MIDI input. Dumps are handed to the synth
+/// to load before its next buffer, and a dump request is answered by the synth.
+pub fn handle(msg: &[u8]) {
+    match parse(msg) {
+        Some(SysexCommand::DumpRequest) => {
+            info!("SysEx dump request");
+            DUMP_REQUESTED.store(true, Ordering::Relaxed);
+        }
+        Some(SysexCommand::Dump(params)) => {
+            info!("SysEx parameter dump received");
+            LOAD_PARAMS.signal(params);
+        }
+        None => debug!("Ignored SysEx message, {} bytes", msg.len()),
+    }
+}
+
+// Decode a complete SysEx message. Returns `None` for anything that isn't for the
+// synth, or a dump with a bad checksum.
+fn parse(msg: &[u8]) -> Option<SysexCommand> {
+    let [0xF0, MANUFACTURER_ID, MODEL_ID, cmd, data @ .., 0xF7] = msg else {
+        return None;
+    };
+    match *cmd {
+        CMD_DUMP_REQUEST if data.is_empty() => Some(SysexCommand::DumpRequest),
+        CMD_DUMP if data.len() == DUMP_DATA_LEN => {
+            let (nibbles, sum) = data.split_at(DUMP_DATA_LEN - 1);
+            if sum[0] != checksum(nibbles) {
+                return None;
+            }
+            let mut params = [0u8; PARAMS_LEN];
+            for (byte, pair) in params.iter_mut().zip(nibbles.chunks_exact(2)) {
+                *byte = (pair[0] << 4) | (pair[1] & 0x0F);
+            }
+            Some(SysexCommand::Dump(params))
+        }
+        _ => None,
+    }
+}
+
+/// Encode `params` as a dump message, in the format the synth reads back, for
+/// answering a dump request (only over USB, as there's no UART MIDI output)
+#[cfg(not(feature = "uart-midi"))]
+pub fn encode_dump(params: &Params) -> [u8; DUMP_LEN] {
+    let mut msg = [0u8; DUMP_LEN];
+    msg[..4].copy_from_slice(&[0xF0, MANUFACTURER_ID, MODEL_ID, CMD_DUMP]);
+    for (i, byte) in params.iter().enumerate() {
+        msg[4 + 2 * i] = byte >> 4;
+        msg[5 + 2 * i] = byte & 0x0F;
+    }
+    msg[DUMP_LEN - 2] = checksum(&msg[4..DUMP_LEN - 2]);
+    msg[DUMP_LEN - 1] = 0xF7;
+    msg
+}
+
+// 7-bit sum of the data bytes
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) & 0x7F
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `bytes` to an assembler, returning what it makes of the message
+    fn assemble(bytes: &[u8]) -> Option<SysexCommand> {
+        let mut assembler = SysexAssembler::new();
+        let (last, rest) = bytes.split_last()?;
+        for &byte in rest {
+            assert!(assembler.feed(byte).is_none());
+        }
+        parse(assembler.feed(*last)?)
+    }
+
+    // A dump is read back as the parameters it was encoded from
+    #[cfg(not(feature = "uart-midi"))]
+    #[test]
+    fn dump_round_trip() {
+        let mut params = [0u8; PARAMS_LEN];
+        for (i, byte) in params.iter_mut().enumerate() {
+            *byte = (i * 37) as u8;
+        }
+        let msg = encode_dump(&params);
+        assert!(matches!(assemble(&msg), Some(SysexCommand::Dump(p)) if p == params));
+
+        // and not with a bad checksum
+        let mut bad = msg;
+        bad[DUMP_LEN - 2] ^= 1;
+        assert!(assemble(&bad).is_none());
+    }
+
+    #[test]
+    fn dump_request() {
+        let msg = [0xF0, MANUFACTURER_ID, MODEL_ID, CMD_DUMP_REQUEST, 0xF7];
+        assert!(matches!(assemble(&msg), Some(SysexCommand::DumpRequest)));
+        // real-time bytes mid-message are skipped
+        let msg = [
+            0xF0,
+            MANUFACTURER_ID,
+            0xF8,
+            MODEL_ID,
+            CMD_DUMP_REQUEST,
+            0xF7,
+        ];
+        assert!(matches!(assemble(&msg), Some(SysexCommand::DumpRequest)));
+        // for another model
+        let msg = [0xF0, MANUFACTURER_ID, 0x00, CMD_DUMP_REQUEST, 0xF7];
+        assert!(assemble(&msg).is_none());
+    }
+
+    // A message longer than a dump is dropped, rather than cut short
+    #[test]
+    fn overlong_dropped() {
+        let mut assembler = SysexAssembler::new();
+        assembler.feed(0xF0);
+        for _ in 0..DUMP_LEN {
+            assembler.feed(0);
+        }
+        assert!(assembler.feed(0xF7).is_none());
+    }
+}
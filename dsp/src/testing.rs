@@ -0,0 +1,116 @@
+//! Support for the tests: stubs for what the firmware's defmt setup would provide, and
+//! helpers for driving a `Synth` with MIDI and reading back what it renders
+
+extern crate std;
+
+use std::boxed::Box;
+use std::vec::Vec;
+
+use heapless::spsc::{Producer, Queue};
+
+use crate::synth::{MIDI_QUEUE_SIZE, MidiEvent, Synth, WORDS_PER_FRAME, enqueue_midi_event};
+
+// Frames rendered per `process` call. This is fixed rather than `BUFFER_SIZE`, so queued
+// events land on the same frame whichever `buffer-*` feature is enabled.
+pub const CHUNK_FRAMES: usize = 128;
+
+// Nothing reads the log on the host, so it's discarded
+#[defmt::global_logger]
+struct NullLogger;
+
+unsafe impl defmt::Logger for NullLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+/// Move `value` to the heap for the rest of the test run, for things that need a
+/// `'static` home
+pub fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+/// A synth with the power-on sound, fed from its own MIDI queue
+pub struct TestSynth {
+    pub synth: Synth,
+    prod: Producer<'static, MidiEvent, MIDI_QUEUE_SIZE>,
+}
+
+impl TestSynth {
+    pub fn new() -> Self {
+        let (prod, cons) = leak(Queue::new()).split();
+        Self {
+            synth: Synth::new(cons),
+            prod,
+        }
+    }
+
+    /// Queue a MIDI message, to be handled at the start of the next chunk
+    pub fn send(&mut self, status: u8, data1: u8, data2: u8) {
+        enqueue_midi_event(
+            &mut self.prod,
+            MidiEvent {
+                status,
+                data1,
+                data2,
+            },
+        );
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        self.send(0x90, note, velocity);
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.send(0x80, note, 0);
+    }
+
+    pub fn cc(&mut self, cc: u8, value: u8) {
+        self.send(0xB0, cc, value);
+    }
+
+    /// Render `n_chunks` chunks of `CHUNK_FRAMES`, returning each frame's left and right
+    /// samples, scaled as for 16-bit output (full scale is +/-32767)
+    pub fn render(&mut self, n_chunks: usize) -> Vec<(f32, f32)> {
+        (0..n_chunks)
+            .flat_map(|_| self.render_frames(CHUNK_FRAMES))
+            .collect()
+    }
+
+    /// Render `n_frames` frames in one `process` call, for events that need to land
+    /// somewhere other than a chunk boundary
+    pub fn render_frames(&mut self, n_frames: usize) -> Vec<(f32, f32)> {
+        let mut buf = std::vec![0u32; n_frames * WORDS_PER_FRAME];
+        let _ = self.synth.process(&mut buf);
+        buf.chunks(WORDS_PER_FRAME).map(read_frame).collect()
+    }
+}
+
+// Unpack one frame, as written with the default (signed) sample format
+fn read_frame(frame: &[u32]) -> (f32, f32) {
+    #[cfg(not(feature = "i2s-32bit"))]
+    let (l, r) = ((frame[0] >> 16) as i16 as f32, frame[0] as i16 as f32);
+    #[cfg(feature = "i2s-32bit")]
+    let (l, r) = (
+        frame[0] as i32 as f32 / 65536.0,
+        frame[1] as i32 as f32 / 65536.0,
+    );
+    #[cfg(feature = "swap-lr")]
+    let (l, r) = (r, l);
+    (l, r)
+}
+
+/// The largest absolute sample in either channel
+pub fn peak(frames: &[(f32, f32)]) -> f32 {
+    frames
+        .iter()
+        .fold(0.0, |peak: f32, &(l, r)| peak.max(l.abs()).max(r.abs()))
+}
@@ -0,0 +1,83 @@
+// Single-cycle wavetables, built at compile time from harmonic amplitudes. Each table
+// is 256 f32 samples (1 KiB), in flash.
+const WAVETABLE_LEN: usize = 256;
+const N_WAVETABLES: usize = 4;
+
+static WAVETABLES: [[f32; WAVETABLE_LEN]; N_WAVETABLES] = [
+    additive(&[1.0]),                                      // sine
+    additive(&[1.0, 0.7, 0.0, 0.5, 0.0, 0.0, 0.0, 0.3]),   // organ, drawbar-ish
+    additive(&[1.0, 0.5, 0.333, 0.25, 0.2, 0.167, 0.143]), // saw, band limited
+    additive(&[1.0, 0.0, 0.333, 0.0, 0.2, 0.0, 0.143]),    // square, band limited
+];
+
+// sin(2 * PI * phase) for phase in 0.0..1.0, as a Taylor series, since `sin()` isn't
+// available in const context
+const fn const_sin(phase: f64) -> f64 {
+    // sin(2 * PI * phase) = -sin(x), with x in -PI..PI
+    let x = 2.0 * core::f64::consts::PI * (phase - 0.5);
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    let mut n = 1;
+    while n < 10 {
+        term *= -x2 / ((2 * n) as f64 * (2 * n + 1) as f64);
+        sum += term;
+        n += 1;
+    }
+    -sum
+}
+
+// Sum of sine harmonics with the given amplitudes, normalized to a peak of 1.0
+const fn additive(harmonics: &[f64]) -> [f32; WAVETABLE_LEN] {
+    let mut samples = [0.0f64; WAVETABLE_LEN];
+    let mut peak = 0.0f64;
+    let mut i = 0;
+    while i < WAVETABLE_LEN {
+        let mut h = 0;
+        while h < harmonics.len() {
+            let phase = ((h + 1) * i % WAVETABLE_LEN) as f64 / WAVETABLE_LEN as f64;
+            samples[i] += harmonics[h] * const_sin(phase);
+            h += 1;
+        }
+        let abs = if samples[i] < 0.0 {
+            -samples[i]
+        } else {
+            samples[i]
+        };
+        if abs > peak {
+            peak = abs;
+        }
+        i += 1;
+    }
+
+    let mut table = [0.0f32; WAVETABLE_LEN];
+    let mut i = 0;
+    while i < WAVETABLE_LEN {
+        table[i] = (samples[i] / peak) as f32;
+        i += 1;
+    }
+    table
+}
+
+// Read one table at `phase` (0.0 to 1.0), interpolating linearly
+#[inline]
+fn read_table(table: &[f32; WAVETABLE_LEN], phase: f32) -> f32 {
+    let pos = phase * WAVETABLE_LEN as f32;
+    let i = pos as usize;
+    let frac = pos - i as f32;
+    let a = table[i % WAVETABLE_LEN];
+    let b = table[(i + 1) % WAVETABLE_LEN];
+    a + (b - a) * frac
+}
+
+/// Wavetable oscillator output at `phase`, with `position` (0.0 to 1.0) scanning
+/// through the tables and crossfading between neighbours
+#[inline]
+pub fn wavetable(position: f32, phase: f32) -> f32 {
+    let pos = position.clamp(0.0, 1.0) * (N_WAVETABLES - 1) as f32;
+    let i = (pos as usize).min(N_WAVETABLES - 2);
+    let frac = pos - i as f32;
+    let a = read_table(&WAVETABLES[i], phase);
+    let b = read_table(&WAVETABLES[i + 1], phase);
+    a + (b - a) * frac
+}
@@ -1,23 +1,135 @@
-use crate::synth::{MIDI_QUEUE_SIZE, Synth};
-use defmt::info;
+#[cfg(feature = "triple-buffer")]
+use core::sync::atomic::{AtomicU32, Ordering, compiler_fence};
+use defmt::{assert_eq, info, warn};
 use embassy_rp::Peri;
 use embassy_rp::bind_interrupts;
-use embassy_rp::peripherals::PIN_18;
-use embassy_rp::peripherals::PIN_19;
-use embassy_rp::peripherals::PIN_20;
+use embassy_rp::gpio::Pin;
+#[cfg(feature = "triple-buffer")]
+use embassy_rp::pac;
+#[cfg(feature = "triple-buffer")]
+use embassy_rp::pac::dma::vals::{DataSize, TreqSel};
 use embassy_rp::peripherals::PIO0;
 use embassy_rp::peripherals::{DMA_CH0, DMA_CH1, DMA_CH2};
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::pio_programs::i2s::{PioI2sOut, PioI2sOutProgram};
+#[cfg(feature = "triple-buffer")]
+use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
+#[cfg(feature = "triple-buffer")]
+use oxynth_dsp::synth::N_BUFFERS;
+use oxynth_dsp::synth::{
+    BUFFER_SIZE, MIDI_QUEUE_SIZE, Params, SAMPLE_RATE, SampleFormat, Synth, WORDS_PER_FRAME,
+};
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
 });
 
-const SAMPLE_RATE: u32 = 48_000;
+// Bits per channel. With 32 bits each channel gets its own DMA word.
+#[cfg(not(feature = "i2s-32bit"))]
 const BIT_DEPTH: u32 = 16;
-const BUFFER_SIZE: usize = 512;
+#[cfg(feature = "i2s-32bit")]
+const BIT_DEPTH: u32 = 32;
+
+// I2S pins (bit clock, LR clock, data), matching the wiring in the README. To use other
+// pins, change them in the last arm; the pin types and the pins `main` takes from the
+// peripherals both come from there. Any GPIOs will do, except that the PIO program
+// drives both clocks by side-set, which needs the LR clock on the pin straight after
+// the bit clock.
+//
+// `i2s_pins!(p)` takes the pins from the peripherals `p`, for `audio_task`.
+macro_rules! i2s_pins {
+    (@pins [$bit_clock:ident, $lr_clock:ident, $data:ident] types) => {
+        type BitClockPin = embassy_rp::peripherals::$bit_clock;
+        type LrClockPin = embassy_rp::peripherals::$lr_clock;
+        type DataPin = embassy_rp::peripherals::$data;
+    };
+    (@pins [$bit_clock:ident, $lr_clock:ident, $data:ident] $p:expr) => {
+        ($p.$bit_clock, $p.$lr_clock, $p.$data)
+    };
+    ($($arg:tt)+) => {
+        $crate::audio_out::i2s_pins! { @pins [PIN_18, PIN_19, PIN_20] $($arg)+ }
+    };
+}
+pub(crate) use i2s_pins;
+
+i2s_pins!(types);
+
+// Words in one output buffer
+const BUFFER_LEN: usize = BUFFER_SIZE * WORDS_PER_FRAME;
+
+// With triple buffering the DMA channels are driven through the PAC rather than by
+// embassy, so their numbers are needed. They're DMA_CH0 and DMA_CH1, which `audio_task`
+// takes so nothing else can use them.
+#[cfg(feature = "triple-buffer")]
+const DATA_DMA_CH: usize = 0;
+#[cfg(feature = "triple-buffer")]
+const CTRL_DMA_CH: usize = 1;
+
+// Start address of the buffer ring, which the control DMA channel writes back into the
+// data channel to start it again from the top
+#[cfg(feature = "triple-buffer")]
+static RING_START: AtomicU32 = AtomicU32::new(0);
+
+/// Play the `len` words from `ring` to the I2S state machine's FIFO, over and over,
+/// without the CPU. The data channel plays the whole ring then chains to the control
+/// channel, which writes the ring's start address back to the data channel, triggering
+/// it again. The PIO FIFO covers the couple of cycles that takes.
+#[cfg(feature = "triple-buffer")]
+fn start_ring_dma(ring: *const u32, len: usize) {
+    RING_START.store(ring as u32, Ordering::Relaxed);
+    let data = pac::DMA.ch(DATA_DMA_CH);
+    let ctrl = pac::DMA.ch(CTRL_DMA_CH);
+
+    ctrl.read_addr().write_value(RING_START.as_ptr() as u32);
+    ctrl.write_addr()
+        .write_value(data.al3_read_addr_trig().as_ptr() as u32);
+    ctrl.trans_count().write(|w| w.set_count(1));
+    ctrl.al1_ctrl().write(|w| {
+        w.set_treq_sel(TreqSel::PERMANENT);
+        w.set_data_size(DataSize::SIZE_WORD);
+        w.set_incr_read(false);
+        w.set_incr_write(false);
+        w.set_chain_to(CTRL_DMA_CH as u8); // chaining to itself is no chain
+        w.set_irq_quiet(true);
+        w.set_en(true);
+    });
+
+    data.write_addr()
+        .write_value(pac::PIO0.txf(0).as_ptr() as u32);
+    data.trans_count().write(|w| w.set_count(len as u32));
+    data.al1_ctrl().write(|w| {
+        w.set_treq_sel(TreqSel::PIO0_TX0);
+        w.set_data_size(DataSize::SIZE_WORD);
+        w.set_incr_read(true);
+        w.set_incr_write(false);
+        w.set_chain_to(CTRL_DMA_CH as u8);
+        w.set_irq_quiet(true);
+        w.set_en(true);
+    });
+    compiler_fence(Ordering::SeqCst);
+    data.al3_read_addr_trig().write_value(ring as u32);
+}
+
+/// Which buffer of the ring starting at `ring` the DMA is playing
+#[cfg(feature = "triple-buffer")]
+fn playing_buffer(ring: *const u32) -> usize {
+    let read_addr = pac::DMA.ch(DATA_DMA_CH).read_addr().read() as usize;
+    let offset = read_addr.wrapping_sub(ring as usize) / core::mem::size_of::<u32>();
+    // just past the end, while the control channel restarts it, counts as the last
+    (offset / BUFFER_LEN).min(N_BUFFERS - 1)
+}
+
+fn log_underrun(elapsed: Duration, budget: Duration, underruns: u32) {
+    warn!(
+        "Audio underrun: buffer fill took {} us (budget {} us), {} underruns so far. If they keep happening, try a longer buffer than {} samples",
+        elapsed.as_micros(),
+        budget.as_micros(),
+        underruns,
+        BUFFER_SIZE
+    );
+}
 
 #[embassy_executor::task]
 pub async fn audio_task(
@@ -25,25 +137,26 @@ pub async fn audio_task(
     dma_ch0: Peri<'static, DMA_CH0>,
     dma_ch1: Peri<'static, DMA_CH1>,
     dma_ch2: Peri<'static, DMA_CH2>,
-    pin18: Peri<'static, PIN_18>,
-    pin19: Peri<'static, PIN_19>,
-    pin20: Peri<'static, PIN_20>,
-    midi_consumer: heapless::spsc::Consumer<'static, crate::synth::MidiEvent, MIDI_QUEUE_SIZE>,
+    bit_clock_pin: Peri<'static, BitClockPin>,
+    left_right_clock_pin: Peri<'static, LrClockPin>,
+    data_pin: Peri<'static, DataPin>,
+    midi_consumer: heapless::spsc::Consumer<'static, oxynth_dsp::synth::MidiEvent, MIDI_QUEUE_SIZE>,
+    saved_params: Option<Params>,
+    sample_format: SampleFormat,
 ) {
     info!("Starting I2S audio output task");
     let Pio {
         mut common, sm0, ..
     } = Pio::new(pio0, Irqs);
 
-    let bit_clock_pin = pin18;
-    let left_right_clock_pin = pin19;
-    let data_pin = pin20;
+    assert_eq!(
+        left_right_clock_pin.pin(),
+        bit_clock_pin.pin() + 1,
+        "The I2S LR clock must be on the pin after the bit clock"
+    );
 
     let program = PioI2sOutProgram::new(&mut common);
 
-    let mut buf_a = [0u32; BUFFER_SIZE];
-    let mut buf_b = [0u32; BUFFER_SIZE];
-
     let mut i2s = PioI2sOut::new(
         &mut common,
         sm0,
@@ -57,13 +170,103 @@ pub async fn audio_task(
     );
 
     let mut synth = Synth::new(midi_consumer);
+    synth.set_sample_format(sample_format);
+    if let Some(params) = saved_params
+        && synth.load_params(&params)
+    {
+        info!("Loaded saved parameters");
+    }
+    #[cfg(feature = "benchmark")]
+    crate::benchmark::run(&mut synth);
+    #[cfg(feature = "startup-chime")]
+    let mut chime = crate::chime::StartupChime::new();
+
+    #[cfg(feature = "cpu-load")]
+    let mut cpu_load = crate::cpu_load::CpuLoad::new();
+    // Render a buffer, and time it
+    let mut fill = move |buf: &mut [u32]| {
+        #[cfg(feature = "startup-chime")]
+        chime.tick(&mut synth);
+        let start = Instant::now();
+        let result = synth.process(buf);
+        let elapsed = start.elapsed();
+        #[cfg(feature = "cpu-load")]
+        cpu_load.add(elapsed);
+        (result, elapsed)
+    };
+
+    // If filling a buffer takes longer than playing one, the DMA runs out of samples
+    let buffer_duration =
+        Duration::from_micros(BUFFER_SIZE as u64 * 1_000_000 / SAMPLE_RATE as u64);
+    let mut underruns: u32 = 0;
+
+    #[cfg(not(feature = "triple-buffer"))]
+    {
+        let mut buf_a = [0u32; BUFFER_LEN];
+        let mut buf_b = [0u32; BUFFER_LEN];
+        i2s.stream_ping_pong(
+            dma_ch0,
+            dma_ch1,
+            &mut buf_a,
+            &mut buf_b,
+            move |buf: &mut [u32]| {
+                let (result, elapsed) = fill(buf);
+                if elapsed > buffer_duration {
+                    underruns = underruns.wrapping_add(1);
+                    log_underrun(elapsed, buffer_duration, underruns);
+                }
+                result
+            },
+        )
+        .await;
+    }
 
-    i2s.stream_ping_pong(
-        dma_ch0,
-        dma_ch1,
-        &mut buf_a,
-        &mut buf_b,
-        move |buf: &mut [u32]| synth.process(buf),
-    )
-    .await;
+    // Triple buffering: the DMA plays a ring of buffers by itself, and each buffer is
+    // filled once the DMA has moved on to the next one. That leaves two buffers' time to
+    // fill it before the DMA gets back round, so a late render only underruns if it's
+    // more than a buffer late.
+    #[cfg(feature = "triple-buffer")]
+    {
+        // The DMA writes straight to the I2S state machine's FIFO, so `i2s` only keeps the
+        // state machine set up, and the channels are held so nothing else uses them
+        let _reserved = (&mut i2s, dma_ch0, dma_ch1);
+        let mut ring = [0u32; N_BUFFERS * BUFFER_LEN];
+        let ring = ring.as_mut_ptr();
+        let poll_interval = buffer_duration / 8;
+        let mut late_renders: u32 = 0;
+        start_ring_dma(ring, N_BUFFERS * BUFFER_LEN);
+        // the DMA starts on the first buffer, which is silent, so fill the others ahead
+        let mut next = 1;
+        loop {
+            while playing_buffer(ring) == next {
+                Timer::after(poll_interval).await;
+            }
+            let before = playing_buffer(ring);
+            // SAFETY: the DMA isn't reading this buffer, and won't be until it has played
+            // the ones in between
+            let buf =
+                unsafe { core::slice::from_raw_parts_mut(ring.add(next * BUFFER_LEN), BUFFER_LEN) };
+            let (result, elapsed) = fill(buf);
+            if result.is_break() {
+                break;
+            }
+            // The DMA has underrun if it got to this buffer before it was filled
+            let moved = (playing_buffer(ring) + N_BUFFERS - before) % N_BUFFERS;
+            let ahead = (next + N_BUFFERS - before) % N_BUFFERS;
+            if moved >= ahead {
+                underruns = underruns.wrapping_add(1);
+                log_underrun(elapsed, buffer_duration, underruns);
+            } else if elapsed > buffer_duration {
+                late_renders = late_renders.wrapping_add(1);
+                info!(
+                    "Late buffer fill took {} us (budget {} us), covered by triple buffering: {} late fills, {} underruns so far",
+                    elapsed.as_micros(),
+                    buffer_duration.as_micros(),
+                    late_renders,
+                    underruns
+                );
+            }
+            next = (next + 1) % N_BUFFERS;
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use defmt::info;
+use embassy_time::{Duration, Instant};
+use oxynth_dsp::synth::{
+    BUFFER_SIZE, MidiEvent, N_BUFFERS, N_VOICES, SAMPLE_RATE, Synth, WORDS_PER_FRAME,
+};
+
+const WARMUP_BUFFERS: u32 = 8;
+const TIMED_BUFFERS: u32 = 200;
+// Voices playing for the light load timing, which shows what idle voices cost
+const LIGHT_LOAD_VOICES: u8 = 2;
+// Buffer lengths tried for the shortest safe buffer, up to BUFFER_SIZE
+const CANDIDATE_BUFFER_SIZES: [usize; 4] = [64, 128, 256, 512];
+// Share of a buffer's play time the worst render may take for the buffer length to count
+// as safe, leaving the rest for MIDI handling on the same core and timing jitter
+const SAFE_LOAD_PERCENT: u64 = 75;
+
+// A heavy but realistic patch: saw, both oscillators, sub, resonant filter
+const SETUP_CCS: [(u8, u8); 6] = [
+    (21, 50),  // sawtooth
+    (36, 64),  // osc mix
+    (35, 20),  // osc detune
+    (58, 64),  // sub osc level
+    (26, 64),  // filter cutoff
+    (27, 100), // filter resonance
+];
+
+fn send(synth: &mut Synth, status: u8, data1: u8, data2: u8) {
+    synth.handle_event(&MidiEvent {
+        status,
+        data1,
+        data2,
+    });
+}
+
+fn play_notes(synth: &mut Synth, n_voices: u8) {
+    // All Sound Off, so only the new notes are playing
+    send(synth, 0xB0, 120, 0);
+    for i in 0..n_voices {
+        send(synth, 0x90, 36 + 3 * i, 100);
+    }
+}
+
+// Render buffers of `frames` frames, returning the mean and worst time per buffer
+fn time_buffers(synth: &mut Synth, frames: usize) -> (Duration, Duration) {
+    let mut buf = [0u32; BUFFER_SIZE * WORDS_PER_FRAME];
+    let buf = &mut buf[..frames * WORDS_PER_FRAME];
+    for _ in 0..WARMUP_BUFFERS {
+        let _ = synth.process(buf);
+    }
+    let mut total = Duration::from_ticks(0);
+    let mut worst = Duration::from_ticks(0);
+    for _ in 0..TIMED_BUFFERS {
+        let start = Instant::now();
+        let _ = synth.process(buf);
+        let elapsed = start.elapsed();
+        total += elapsed;
+        worst = worst.max(elapsed);
+    }
+    (total / TIMED_BUFFERS, worst)
+}
+
+fn buffer_duration_us(frames: usize) -> u64 {
+    frames as u64 * 1_000_000 / SAMPLE_RATE as u64
+}
+
+// Play `n_voices` notes, and log the mean and worst render time per buffer against the
+// time the buffer takes to play. Returns the mean.
+fn time_render(synth: &mut Synth, n_voices: u8) -> Duration {
+    play_notes(synth, n_voices);
+    let (mean, worst) = time_buffers(synth, BUFFER_SIZE);
+    let budget_us = buffer_duration_us(BUFFER_SIZE);
+    let mean_us = mean.as_micros();
+    info!(
+        "Benchmark: {} of {} voices, {} us mean, {} us worst per buffer, budget {} us ({}% headroom)",
+        n_voices,
+        N_VOICES,
+        mean_us,
+        worst.as_micros(),
+        budget_us,
+        100 - (100 * mean_us / budget_us).min(100)
+    );
+    mean
+}
+
+// Compare the light load with the full one, splitting the render time into a cost per
+// playing voice and a fixed cost per buffer. Idle voices are skipped, so the light load
+// should cost the fixed part plus its own couple of voices, and nothing for the rest.
+fn compare_loads(full: Duration, light: Duration) {
+    let (full_us, light_us) = (full.as_micros(), light.as_micros());
+    let per_voice_us =
+        full_us.saturating_sub(light_us) / (N_VOICES as u64 - LIGHT_LOAD_VOICES as u64);
+    let fixed_us = light_us.saturating_sub(per_voice_us * LIGHT_LOAD_VOICES as u64);
+    info!(
+        "Benchmark: {} voices take {}% of the time {} do, ~{} us per voice plus ~{} us per buffer",
+        LIGHT_LOAD_VOICES,
+        100 * light_us / full_us.max(1),
+        N_VOICES,
+        per_voice_us,
+        fixed_us
+    );
+}
+
+// With every voice playing, time shorter buffers and log the shortest one whose worst
+// render stays within SAFE_LOAD_PERCENT of its play time. Shorter buffers cost a bit
+// more per sample, as the MIDI handling and per-buffer setup are spread over fewer
+// samples, so this can't just be worked out from the full length timing.
+fn find_shortest_buffer(synth: &mut Synth) {
+    play_notes(synth, N_VOICES as u8);
+    let mut shortest = None;
+    for frames in CANDIDATE_BUFFER_SIZES.into_iter().rev() {
+        if frames > BUFFER_SIZE {
+            continue;
+        }
+        let (_, worst) = time_buffers(synth, frames);
+        let budget_us = buffer_duration_us(frames);
+        info!(
+            "Benchmark: {}-sample buffers, {} us worst, budget {} us",
+            frames,
+            worst.as_micros(),
+            budget_us
+        );
+        if 100 * worst.as_micros() > SAFE_LOAD_PERCENT * budget_us {
+            break;
+        }
+        shortest = Some(frames);
+    }
+    match shortest {
+        Some(frames) => info!(
+            "Benchmark: shortest safe buffer is {} samples, {} us output latency",
+            frames,
+            N_BUFFERS as u64 * buffer_duration_us(frames)
+        ),
+        None => info!(
+            "Benchmark: no buffer length is safe with {} voices",
+            N_VOICES
+        ),
+    }
+}
+
+/// Time `Synth::process` with every voice playing, then with just a couple, and log the
+/// render time per buffer against the time the buffer takes to play, and what the two
+/// loads say each voice costs. Then look for the shortest buffer length (no longer than
+/// BUFFER_SIZE) that's safe at full polyphony.
+/// Run on the audio core (with the `benchmark` feature) before audio starts, so the
+/// numbers are repeatable. The synth's sound parameters are put back afterwards.
+pub fn run(synth: &mut Synth) {
+    let saved = synth.save_params();
+    for (cc_num, cc_val) in SETUP_CCS {
+        send(synth, 0xB0, cc_num, cc_val);
+    }
+    let full = time_render(synth, N_VOICES as u8);
+    let light = time_render(synth, LIGHT_LOAD_VOICES);
+    compare_loads(full, light);
+    find_shortest_buffer(synth);
+
+    // All Sound Off, then restore the sound
+    send(synth, 0xB0, 120, 0);
+    synth.load_params(&saved);
+}
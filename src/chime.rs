@@ -0,0 +1,49 @@
+use defmt::info;
+use oxynth_dsp::synth::{BUFFER_SIZE, MidiEvent, SAMPLE_RATE, Synth};
+
+// A rising C major arpeggio, one note per step
+const NOTES: [u8; 4] = [60, 64, 67, 72];
+const VELOCITY: u8 = 100;
+const STEP_S: f32 = 0.15;
+const STEP_BUFFERS: u32 = (STEP_S * SAMPLE_RATE as f32) as u32 / BUFFER_SIZE as u32;
+
+/// Plays a short arpeggio through the synth at power up, before any MIDI arrives, to
+/// check the DAC wiring and clocks without a controller attached (`startup-chime`
+/// feature). It plays the synth's current sound.
+pub struct StartupChime {
+    buffers: u32,
+}
+
+impl StartupChime {
+    pub const fn new() -> Self {
+        Self { buffers: 0 }
+    }
+
+    /// Call before rendering each buffer. Sends the arpeggio's notes to the synth at
+    /// the right buffers, and does nothing once it's finished.
+    pub fn tick(&mut self, synth: &mut Synth) {
+        let step = (self.buffers / STEP_BUFFERS) as usize;
+        if step > NOTES.len() {
+            return;
+        }
+        if self.buffers.is_multiple_of(STEP_BUFFERS) {
+            if step == 0 {
+                info!("Playing startup chime");
+            } else {
+                send(synth, 0x80, NOTES[step - 1], 0);
+            }
+            if let Some(&note) = NOTES.get(step) {
+                send(synth, 0x90, note, VELOCITY);
+            }
+        }
+        self.buffers += 1;
+    }
+}
+
+fn send(synth: &mut Synth, status: u8, data1: u8, data2: u8) {
+    synth.handle_event(&MidiEvent {
+        status,
+        data1,
+        data2,
+    });
+}
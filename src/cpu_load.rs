@@ -0,0 +1,48 @@
+use defmt::info;
+use embassy_time::Duration;
+
+use oxynth_dsp::synth::{BUFFER_SIZE, SAMPLE_RATE};
+
+// Time one buffer takes to play, which is the time available to fill the next one
+const BUFFER_US: u64 = BUFFER_SIZE as u64 * 1_000_000 / SAMPLE_RATE as u64;
+// Buffers in about a second of output
+const REPORT_BUFFERS: u32 = SAMPLE_RATE / BUFFER_SIZE as u32;
+
+/// CPU load of the audio render, as the share of each buffer's play time spent filling
+/// it. Averaged over about a second of buffers, and logged with the busiest buffer, to
+/// show how much headroom is left.
+pub struct CpuLoad {
+    busy_us: u64,
+    peak_us: u64,
+    n_buffers: u32,
+}
+
+impl CpuLoad {
+    pub const fn new() -> Self {
+        Self {
+            busy_us: 0,
+            peak_us: 0,
+            n_buffers: 0,
+        }
+    }
+
+    /// Add the time a buffer took to fill, and log the load and start again once about
+    /// a second's worth of buffers have been added
+    pub fn add(&mut self, elapsed: Duration) {
+        let us = elapsed.as_micros();
+        self.busy_us += us;
+        self.peak_us = self.peak_us.max(us);
+        self.n_buffers += 1;
+        if self.n_buffers < REPORT_BUFFERS {
+            return;
+        }
+        let budget_us = BUFFER_US * self.n_buffers as u64;
+        info!(
+            "CPU load {}% mean, {}% peak ({} us per buffer)",
+            100.0 * self.busy_us as f32 / budget_us as f32,
+            100.0 * self.peak_us as f32 / BUFFER_US as f32,
+            BUFFER_US
+        );
+        *self = Self::new();
+    }
+}
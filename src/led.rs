@@ -0,0 +1,24 @@
+use core::sync::atomic::Ordering;
+use embassy_rp::gpio::{Level, Output};
+use embassy_time::{Duration, Timer};
+use oxynth_dsp::synth::VOICES_SOUNDING;
+use {defmt_rtt as _, panic_probe as _};
+
+// How often the LED catches up with the synth. Fast enough that short notes still show,
+// as a flicker.
+const LED_POLL: Duration = Duration::from_millis(20);
+
+/// Lights the onboard LED while any voice is sounding, so a headless board shows at a
+/// glance whether MIDI is getting through
+#[embassy_executor::task]
+pub async fn led_task(mut led: Output<'static>) {
+    loop {
+        let level = if VOICES_SOUNDING.load(Ordering::Relaxed) {
+            Level::High
+        } else {
+            Level::Low
+        };
+        led.set_level(level);
+        Timer::after(LED_POLL).await;
+    }
+}
@@ -1,25 +1,64 @@
 #![no_std]
 #![no_main]
 
+#[cfg(not(feature = "pwm-audio"))]
 mod audio_out;
-mod synth;
+#[cfg(feature = "benchmark")]
+mod benchmark;
+#[cfg(feature = "startup-chime")]
+mod chime;
+#[cfg(feature = "cpu-load")]
+mod cpu_load;
+mod led;
+mod patch_store;
+#[cfg(feature = "pwm-audio")]
+mod pwm_out;
+#[cfg(feature = "uart-midi")]
+mod uart_midi_in;
+#[cfg(not(feature = "uart-midi"))]
 mod usb_midi_in;
 
-use audio_out::audio_task;
+#[cfg(not(feature = "pwm-audio"))]
+use audio_out::{audio_task, i2s_pins};
 use heapless::spsc::Queue;
+use led::led_task;
+#[cfg(not(feature = "pwm-audio"))]
+use oxynth_dsp::synth::SampleFormat;
+use oxynth_dsp::synth::{BUFFER_SIZE, MIDI_QUEUE, N_BUFFERS, Synth, WORDS_PER_FRAME};
+use patch_store::patch_store_task;
+#[cfg(feature = "pwm-audio")]
+use pwm_out::audio_task;
 use static_cell::StaticCell;
-use synth::MIDI_QUEUE;
+#[cfg(feature = "uart-midi")]
+use uart_midi_in::uart_input_task;
+#[cfg(not(feature = "uart-midi"))]
 use usb_midi_in::usb_input_task;
 
 use defmt::*;
 use embassy_executor::Executor;
+use embassy_rp::flash::Flash;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::multicore::{Stack, spawn_core1};
 use {defmt_rtt as _, panic_probe as _};
 
-// NB if you start seeing mysterious crashes, it could be that core1's stack isn't big enough
-// for 2x BUFFER_SIZE u32 buffers + synth state etc.
-static mut CORE1_STACK: Stack<16384> = Stack::new();
+// Core1's stack is sized for N_BUFFERS x BUFFER_SIZE frame buffers + synth state, plus a
+// margin for everything else. NB if you start seeing mysterious crashes, it could be that
+// the margin isn't big enough.
+const CORE1_STACK_MARGIN: usize = 10 * 1024;
+const CORE1_STACK_SIZE: usize =
+    N_BUFFERS * BUFFER_SIZE * WORDS_PER_FRAME * core::mem::size_of::<u32>()
+        + core::mem::size_of::<Synth>()
+        + CORE1_STACK_MARGIN;
+// How the I2S DAC expects its samples. The PCM5102 takes signed samples, build with
+// `offset-binary` for DACs that want offset binary instead.
+#[cfg(not(feature = "pwm-audio"))]
+const SAMPLE_FORMAT: SampleFormat = if cfg!(feature = "offset-binary") {
+    SampleFormat::OffsetBinary
+} else {
+    SampleFormat::Signed
+};
+
+static mut CORE1_STACK: Stack<CORE1_STACK_SIZE> = Stack::new();
 static EXECUTOR0: StaticCell<Executor> = StaticCell::new();
 static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
 
@@ -27,28 +66,61 @@ static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
 fn main() -> ! {
     let p = embassy_rp::init(Default::default());
     info!("Starting USB MIDI synth POC");
-    let mut led = Output::new(p.PIN_25, Level::Low);
-    led.set_high();
+    let led = Output::new(p.PIN_25, Level::Low);
 
     // MIDI queue producer and consumer
     let queue = MIDI_QUEUE.init(Queue::new());
     let (prod, cons) = queue.split();
 
+    // Load the saved sound, if any, for the synth to start up with
+    let mut flash = Flash::new_blocking(p.FLASH);
+    let saved_params = patch_store::load_params(&mut flash);
+
     // Realtime audio processing goes on core 1
     spawn_core1(
         p.CORE1,
         unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK) },
         move || {
             let executor1 = EXECUTOR1.init(Executor::new());
+            #[cfg(not(feature = "pwm-audio"))]
+            executor1.run(|spawner| {
+                let (bit_clock_pin, left_right_clock_pin, data_pin) = i2s_pins!(p);
+                spawner.spawn(unwrap!(audio_task(
+                    p.PIO0,
+                    p.DMA_CH0,
+                    p.DMA_CH1,
+                    p.DMA_CH2,
+                    bit_clock_pin,
+                    left_right_clock_pin,
+                    data_pin,
+                    cons,
+                    saved_params,
+                    SAMPLE_FORMAT
+                )))
+            });
+            #[cfg(feature = "pwm-audio")]
             executor1.run(|spawner| {
                 spawner.spawn(unwrap!(audio_task(
-                    p.PIO0, p.DMA_CH0, p.DMA_CH1, p.DMA_CH2, p.PIN_18, p.PIN_19, p.PIN_20, cons
+                    p.PWM_SLICE1,
+                    p.PIN_2,
+                    p.PIN_3,
+                    p.DMA_CH0,
+                    cons,
+                    saved_params
                 )))
             });
         },
     );
 
-    // Anything non-realtime (currently USB MIDI input) goes on core 0
+    // Anything non-realtime (MIDI input, saving to flash, the LED) goes on core 0. The
+    // MIDI queue has a single producer, so it's either USB or UART input.
     let executor0 = EXECUTOR0.init(Executor::new());
-    executor0.run(|spawner| spawner.spawn(unwrap!(usb_input_task(p.USB, prod))));
+    executor0.run(|spawner| {
+        spawner.spawn(unwrap!(patch_store_task(flash)));
+        spawner.spawn(unwrap!(led_task(led)));
+        #[cfg(not(feature = "uart-midi"))]
+        spawner.spawn(unwrap!(usb_input_task(p.USB, prod)));
+        #[cfg(feature = "uart-midi")]
+        spawner.spawn(unwrap!(uart_input_task(p.UART0, p.PIN_1, prod)));
+    });
 }
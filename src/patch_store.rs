@@ -0,0 +1,71 @@
+use defmt::*;
+use embassy_rp::flash::{Blocking, ERASE_SIZE, Flash};
+use embassy_rp::peripherals::FLASH;
+use oxynth_dsp::synth::{PARAMS_LEN, Params, SAVE_PARAMS};
+use {defmt_rtt as _, panic_probe as _};
+
+// Must match the flash size in memory.x, which leaves the last sector out of the
+// program image so it can hold the saved parameters
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+const STORE_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+// Stored record: magic, parameter block, CRC-32 of the magic and block
+const MAGIC: [u8; 4] = *b"OXYP";
+const RECORD_LEN: usize = MAGIC.len() + PARAMS_LEN + 4;
+
+pub type SynthFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// Read the last saved parameter block, if there is a valid one. A blank sector or
+/// a corrupt record reads as `None`. The block's layout version is checked when it's
+/// loaded into the synth.
+pub fn load_params(flash: &mut SynthFlash) -> Option<Params> {
+    let mut record = [0u8; RECORD_LEN];
+    if let Err(e) = flash.blocking_read(STORE_OFFSET, &mut record) {
+        warn!("Flash read failed: {:?}", e);
+        return None;
+    }
+    let (body, crc) = record.split_at(RECORD_LEN - 4);
+    if body[..MAGIC.len()] != MAGIC || crc32(body).to_le_bytes() != crc {
+        info!("No saved parameters in flash");
+        return None;
+    }
+    let mut params = [0u8; PARAMS_LEN];
+    params.copy_from_slice(&body[MAGIC.len()..]);
+    Some(params)
+}
+
+/// Writes parameter blocks to flash when the synth asks for a save (MIDI CC 106).
+/// Erasing and programming is blocking, and core 1 is paused while it happens, so
+/// expect a short dropout in the audio.
+#[embassy_executor::task]
+pub async fn patch_store_task(mut flash: SynthFlash) {
+    loop {
+        let params = SAVE_PARAMS.wait().await;
+        let mut record = [0u8; RECORD_LEN];
+        record[..MAGIC.len()].copy_from_slice(&MAGIC);
+        record[MAGIC.len()..RECORD_LEN - 4].copy_from_slice(&params);
+        let crc = crc32(&record[..RECORD_LEN - 4]);
+        record[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        let result = flash
+            .blocking_erase(STORE_OFFSET, STORE_OFFSET + ERASE_SIZE as u32)
+            .and_then(|_| flash.blocking_write(STORE_OFFSET, &record));
+        match result {
+            Ok(()) => info!("Parameters saved to flash"),
+            Err(e) => warn!("Saving parameters to flash failed: {:?}", e),
+        }
+    }
+}
+
+// CRC-32 (IEEE), bitwise since it only runs on a save or at boot
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,109 @@
+use defmt::{info, warn};
+use embassy_rp::Peri;
+use embassy_rp::dma;
+use embassy_rp::pac;
+use embassy_rp::peripherals::{DMA_CH0, PIN_2, PIN_3, PWM_SLICE1};
+use embassy_rp::pwm::{Config, Pwm};
+#[cfg(feature = "cpu-load")]
+use embassy_time::Instant;
+use oxynth_dsp::synth::{BUFFER_SIZE, MIDI_QUEUE_SIZE, Params, SAMPLE_RATE, Synth};
+use {defmt_rtt as _, panic_probe as _};
+
+// PWM counter wraps at PWM_TOP, so samples get 10 bits of resolution and the carrier is
+// clk_sys / 1024 (~146 kHz at 150 MHz), well above the audio band. Each output pin
+// wants a simple RC low-pass filter (eg. 1k + 10nF) before the amp.
+const PWM_TOP: u16 = 1023;
+const PWM_SLICE: usize = 1;
+
+// Convert a signed 16-bit sample to a PWM compare level
+#[inline]
+fn duty(sample: i16) -> u32 {
+    ((sample as i32 + 32768) as u32 * (PWM_TOP as u32 + 1)) >> 16
+}
+
+// The synth packs left into the high half of each word and right into the low half.
+// The PWM CC register wants channel A (left, GPIO2) low and channel B (right, GPIO3) high.
+fn to_pwm_levels(buf: &mut [u32]) {
+    for w in buf.iter_mut() {
+        let l = (*w >> 16) as u16 as i16;
+        let r = *w as u16 as i16;
+        *w = duty(l) | (duty(r) << 16);
+    }
+}
+
+/// Alternative to the I2S `audio_task` for boards without a DAC: plays the synth as PWM
+/// on GPIO2/GPIO3. DMA writes one sample per period of a DMA pacing timer running at
+/// SAMPLE_RATE, while the next buffer is rendered.
+#[embassy_executor::task]
+pub async fn audio_task(
+    slice: Peri<'static, PWM_SLICE1>,
+    pin2: Peri<'static, PIN_2>,
+    pin3: Peri<'static, PIN_3>,
+    mut dma_ch0: Peri<'static, DMA_CH0>,
+    midi_consumer: heapless::spsc::Consumer<'static, oxynth_dsp::synth::MidiEvent, MIDI_QUEUE_SIZE>,
+    saved_params: Option<Params>,
+) {
+    info!("Starting PWM audio output task");
+
+    let mut config = Config::default();
+    config.top = PWM_TOP;
+    config.compare_a = PWM_TOP / 2;
+    config.compare_b = PWM_TOP / 2;
+    let _pwm = Pwm::new_output_ab(slice, pin2, pin3, config);
+
+    // Pace the DMA at SAMPLE_RATE, as a fraction X/Y of clk_sys
+    let clk_sys = embassy_rp::clocks::clk_sys_freq();
+    if clk_sys % SAMPLE_RATE != 0 {
+        warn!(
+            "clk_sys {} Hz isn't a multiple of {} Hz, pitch will be slightly off",
+            clk_sys, SAMPLE_RATE
+        );
+    }
+    pac::DMA.timer(0).write(|w| {
+        w.set_x(1);
+        w.set_y((clk_sys / SAMPLE_RATE) as u16);
+    });
+    let cc = pac::PWM.ch(PWM_SLICE).cc().as_ptr() as *mut u32;
+
+    let mut synth = Synth::new(midi_consumer);
+    if let Some(params) = saved_params
+        && synth.load_params(&params)
+    {
+        info!("Loaded saved parameters");
+    }
+    #[cfg(feature = "benchmark")]
+    crate::benchmark::run(&mut synth);
+    #[cfg(feature = "startup-chime")]
+    let mut chime = crate::chime::StartupChime::new();
+    let mut buf_a = [0u32; BUFFER_SIZE];
+    let mut buf_b = [0u32; BUFFER_SIZE];
+    let (mut playing, mut next) = (&mut buf_a, &mut buf_b);
+    #[cfg(feature = "cpu-load")]
+    let mut cpu_load = crate::cpu_load::CpuLoad::new();
+
+    #[cfg(feature = "startup-chime")]
+    chime.tick(&mut synth);
+    let _ = synth.process(playing);
+    to_pwm_levels(playing);
+    loop {
+        // SAFETY: `playing` isn't touched again until the transfer has completed
+        let transfer = unsafe {
+            dma::write(
+                dma_ch0.reborrow(),
+                playing.as_slice() as *const [u32],
+                cc,
+                pac::dma::vals::TreqSel::TIMER0,
+            )
+        };
+        #[cfg(feature = "startup-chime")]
+        chime.tick(&mut synth);
+        #[cfg(feature = "cpu-load")]
+        let start = Instant::now();
+        let _ = synth.process(next);
+        to_pwm_levels(next);
+        #[cfg(feature = "cpu-load")]
+        cpu_load.add(start.elapsed());
+        transfer.await;
+        core::mem::swap(&mut playing, &mut next);
+    }
+}
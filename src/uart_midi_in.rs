@@ -0,0 +1,74 @@
+use defmt::*;
+use embassy_rp::Peri;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::{PIN_1, UART0};
+use embassy_rp::uart::{BufferedInterruptHandler, BufferedUartRx, Config};
+use embedded_io_async::Read;
+use heapless::spsc::Producer;
+use oxynth_dsp::midi_parser::{MidiMessage, MidiParser};
+use oxynth_dsp::synth::{MIDI_QUEUE_SIZE, MidiEvent, enqueue_midi_event};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+const MIDI_BAUD_RATE: u32 = 31_250;
+
+static RX_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+
+/// Reads 5-pin DIN MIDI from UART0 RX on GPIO1, as an alternative to USB host input
+#[embassy_executor::task]
+pub async fn uart_input_task(
+    uart: Peri<'static, UART0>,
+    rx_pin: Peri<'static, PIN_1>,
+    mut prod: Producer<'static, MidiEvent, MIDI_QUEUE_SIZE>,
+) -> ! {
+    let mut config = Config::default();
+    config.baudrate = MIDI_BAUD_RATE;
+    let rx_buffer = RX_BUFFER.init([0; 64]);
+    let mut rx = BufferedUartRx::new(uart, Irqs, rx_pin, rx_buffer, config);
+
+    info!("Listening for UART MIDI");
+    let mut parser = MidiParser::new();
+    let mut buf = [0u8; 16];
+    loop {
+        let n = match rx.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("UART MIDI read error: {:?}", e);
+                continue;
+            }
+        };
+
+        for &byte in &buf[..n] {
+            let event = match parser.feed(byte) {
+                Some(MidiMessage::Event(event)) => event,
+                Some(MidiMessage::Sysex(msg)) => {
+                    oxynth_dsp::sysex::handle(msg);
+                    continue;
+                }
+                None => continue,
+            };
+            debug!(
+                "UART MIDI: status={}, data1={}, data2={}",
+                event.status, event.data1, event.data2
+            );
+
+            // Filter the MIDI events we care about, to avoid overflowing the queue
+            match event.status & 0xF0 {
+                0xB0 | 0x90 | 0x80 | 0xE0 | 0xA0 | 0xD0 | 0xC0 => {
+                    enqueue_midi_event(&mut prod, event);
+                }
+                0xF0 if matches!(event.status, 0xF8 | 0xFA | 0xFB | 0xFC) => {
+                    // Clock | Start | Continue | Stop
+                    enqueue_midi_event(&mut prod, event);
+                }
+                _ => {
+                    debug!("Ignored MIDI status={:#X}", event.status);
+                }
+            }
+        }
+    }
+}
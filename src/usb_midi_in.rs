@@ -1,76 +1,264 @@
-use crate::synth::{MIDI_QUEUE_SIZE, MidiEvent as SynthMidiEvent};
 use defmt::*;
+use embassy_futures::select::{Either3, select3};
 use embassy_rp::Peri;
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::USB;
+use embassy_time::{Duration, Timer, with_timeout};
 use embassy_usb::driver::host::DeviceEvent::Connected;
 use embassy_usb::driver::host::UsbHostDriver;
 use embassy_usb::handlers::midi::{MidiEvent as UsbMidiEvent, MidiHandler};
 use embassy_usb::handlers::{HandlerEvent, UsbHostHandler};
 use embassy_usb::host::UsbHostBusExt;
 use heapless::spsc::Producer;
+use oxynth_dsp::midi_out::MIDI_OUT;
+use oxynth_dsp::midi_parser::{MidiMessage, MidiParser};
+use oxynth_dsp::synth::{
+    DUMP_PARAMS, MIDI_QUEUE_SIZE, MidiEvent as SynthMidiEvent, enqueue_midi_event,
+};
+use oxynth_dsp::sysex::encode_dump;
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => embassy_rp::usb::host::InterruptHandler<USB>;
 });
 
+// Enumeration occasionally fails or hangs on a freshly connected device, so it's retried
+// a few times, with an increasing delay, before waiting for the device to be reconnected
+const ENUMERATION_ATTEMPTS: u32 = 5;
+const ENUMERATION_TIMEOUT: Duration = Duration::from_secs(2);
+const ENUMERATION_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Remembers the last value of every controller, so control changes that don't change
+/// anything can be dropped. This stops continuous controllers from crowding notes out of
+/// the MIDI queue.
+struct CcValues {
+    last: [[u8; 128]; 16], // UNKNOWN if not seen yet
+}
+
+impl CcValues {
+    const UNKNOWN: u8 = 0xFF;
+
+    const fn new() -> Self {
+        Self {
+            last: [[Self::UNKNOWN; 128]; 16],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.last = [[Self::UNKNOWN; 128]; 16];
+    }
+
+    /// Record a control change, returning whether it needs to be sent on
+    fn changed(&mut self, status: u8, cc_num: u8, cc_val: u8) -> bool {
+        let channel = (status & 0x0F) as usize;
+        match cc_num {
+            // Channel mode messages (and the save, test tone, panic and state dump CCs)
+            // are commands, which a button may send with the same value every time, and
+            // Data Entry (MSB and LSB) and RPN/NRPN select apply to whichever parameter
+            // is selected, so always pass them through
+            6 | 38 | 98..=101 | 106 | 117..=127 => {
+                if cc_num == 121 {
+                    // Reset All Controllers, which the synth applies to every channel
+                    self.clear();
+                }
+                true
+            }
+            // A controller's LSB refines its latest MSB, so the same LSB can follow a
+            // different MSB
+            #[cfg(feature = "hires-cc")]
+            39 | 58 => true,
+            _ => {
+                let last = &mut self.last[channel][(cc_num & 0x7F) as usize];
+                let changed = *last != cc_val;
+                *last = cc_val;
+                changed
+            }
+        }
+    }
+}
+
+// How many of a USB MIDI event packet's 3 MIDI bytes are used, from the Code Index
+// Number in the low nybble of its first byte. The high nybble is the cable number, which
+// is ignored.
+fn packet_len(header: u8) -> usize {
+    match header & 0x0F {
+        0x0 | 0x1 => 0,       // reserved
+        0x2 | 0xC | 0xD => 2, // 2-byte system common, Program Change, Channel Pressure
+        0x5 | 0xF => 1,       // single byte, or SysEx ends with 1 byte
+        0x6 => 2,             // SysEx ends with 2 bytes
+        _ => 3, // 3-byte system common, SysEx start or continue / ends, channel messages
+    }
+}
+
+// A channel or real-time message as a USB MIDI event packet, on cable 0
+fn usb_packet(event: &SynthMidiEvent) -> [u8; 4] {
+    let cin = match event.status {
+        0xF8..=0xFF => 0xF, // single byte
+        status => status >> 4,
+    };
+    [cin, event.status, event.data1, event.data2]
+}
+
+// Split a complete SysEx message (0xF0 to 0xF7) into USB MIDI event packets
+fn sysex_packets(msg: &[u8]) -> impl Iterator<Item = [u8; 4]> + '_ {
+    let n_chunks = msg.len().div_ceil(3);
+    msg.chunks(3).enumerate().map(move |(i, chunk)| {
+        let cin = if i + 1 < n_chunks {
+            0x4 // SysEx starts or continues
+        } else {
+            0x4 + chunk.len() as u8 // SysEx ends with 1, 2 or 3 bytes
+        };
+        let mut packet = [cin, 0, 0, 0];
+        packet[1..=chunk.len()].copy_from_slice(chunk);
+        packet
+    })
+}
+
+// Queue the MIDI events the synth uses, dropping the rest (and CCs that don't change
+// anything) to avoid overflowing the queue
+fn forward_event(
+    prod: &mut Producer<'static, SynthMidiEvent, MIDI_QUEUE_SIZE>,
+    cc_values: &mut CcValues,
+    event: SynthMidiEvent,
+) {
+    match event.status & 0xF0 {
+        0xB0 if !cc_values.changed(event.status, event.data1, event.data2) => {
+            // CC value unchanged, nothing to do
+        }
+        0xB0 | 0x90 | 0x80 | 0xE0 | 0xA0 | 0xD0 | 0xC0 => {
+            // CC | Note On | Note Off | Pitch Bend | Poly Aftertouch | Channel Pressure
+            // | Program Change
+            enqueue_midi_event(prod, event);
+        }
+        0xF0 if matches!(event.status, 0xF8 | 0xFA | 0xFB | 0xFC) => {
+            // Clock | Start | Continue | Stop, also passed through to the MIDI output
+            enqueue_midi_event(prod, event);
+            oxynth_dsp::midi_out::send(event);
+        }
+        _ => {
+            debug!("Ignored MIDI status={:#X}", event.status);
+        }
+    }
+}
+
 #[embassy_executor::task]
 pub async fn usb_input_task(
     usb: Peri<'static, USB>,
     mut prod: Producer<'static, SynthMidiEvent, MIDI_QUEUE_SIZE>,
 ) -> ! {
     let mut usbhost = embassy_rp::usb::host::Driver::new(*usb, Irqs);
+    let mut cc_values = CcValues::new();
+    let mut parser = MidiParser::new();
 
-    info!("Detecting USB device...");
-    // There seems to be an issue that like one time in ten the device isn't detected
-    // Should investigate and fix that at some point.
-    let speed = loop {
-        match usbhost.wait_for_device_event().await {
-            Connected(speed) => break speed,
-            _ => {}
-        }
-    };
+    loop {
+        info!("Detecting USB device...");
+        let speed = loop {
+            match usbhost.wait_for_device_event().await {
+                Connected(speed) => break speed,
+                _ => {}
+            }
+        };
 
-    println!("Found device with speed = {:?}", speed);
+        println!("Found device with speed = {:?}", speed);
 
-    let enum_info = usbhost.enumerate_root_bare(speed, 1).await.unwrap();
-    let mut midi_device = MidiHandler::try_register(&usbhost, &enum_info)
-        .await
-        .expect("Couldn't register MIDI device");
+        // Each enumeration attempt starts with a fresh bus reset
+        let mut enum_info = None;
+        for attempt in 1..=ENUMERATION_ATTEMPTS {
+            info!(
+                "USB enumeration attempt {}/{}",
+                attempt, ENUMERATION_ATTEMPTS
+            );
+            match with_timeout(ENUMERATION_TIMEOUT, usbhost.enumerate_root_bare(speed, 1)).await {
+                Ok(Ok(found)) => {
+                    enum_info = Some(found);
+                    break;
+                }
+                Ok(Err(e)) => warn!("USB enumeration failed: {:?}", e),
+                Err(_) => warn!("USB enumeration timed out"),
+            }
+            Timer::after(ENUMERATION_RETRY_DELAY * attempt).await;
+        }
+        let Some(enum_info) = enum_info else {
+            warn!("Giving up on USB enumeration, please reconnect the device");
+            continue;
+        };
+        let mut midi_device = match MidiHandler::try_register(&usbhost, &enum_info).await {
+            Ok(midi_device) => midi_device,
+            Err(e) => {
+                warn!("Couldn't register MIDI device: {:?}", e);
+                continue;
+            }
+        };
+        info!("USB MIDI device connected");
+        cc_values.clear();
+        // Anything queued while there was no device is stale
+        MIDI_OUT.clear();
 
-    loop {
-        let result = midi_device.wait_for_event().await;
-        debug!("{:?}", result);
-
-        match result {
-            Ok(HandlerEvent::HandlerEvent(UsbMidiEvent::MidiPacket(pkt))) => {
-                let bytes: [u8; 4] = pkt.data;
-                let status = bytes[1];
-                let data1 = bytes[2];
-                let data2 = bytes[3];
-
-                // Filter the MIDI events we care about, to avoid overflowing the queue
-                // Could also maybe consider rate limiting for continuous controls
-                let status_nybble = status & 0xF0;
-                match status_nybble {
-                    0xB0 | 0x90 | 0x80 => {
-                        // CC | Note On | Note Off
-                        let _ = prod.enqueue(SynthMidiEvent {
-                            status,
-                            data1,
-                            data2,
-                        });
+        loop {
+            // Wait for incoming MIDI, or something to send
+            let result = match select3(
+                midi_device.wait_for_event(),
+                MIDI_OUT.receive(),
+                DUMP_PARAMS.wait(),
+            )
+            .await
+            {
+                Either3::First(result) => result,
+                Either3::Second(event) => {
+                    if let Err(e) = midi_device.send_packet(usb_packet(&event)).await {
+                        warn!("MIDI send error: {:?}", e);
                     }
-                    _ => {
-                        debug!("Ignored MIDI status={:#X}", status);
+                    continue;
+                }
+                Either3::Third(params) => {
+                    info!("Sending SysEx parameter dump");
+                    let dump = encode_dump(&params);
+                    for packet in sysex_packets(&dump) {
+                        if let Err(e) = midi_device.send_packet(packet).await {
+                            warn!("MIDI send error: {:?}", e);
+                            break;
+                        }
                     }
+                    continue;
+                }
+            };
+            debug!("{:?}", result);
+
+            match result {
+                Ok(HandlerEvent::HandlerEvent(UsbMidiEvent::MidiPacket(pkt))) => {
+                    let bytes: [u8; 4] = pkt.data;
+
+                    // Only the bytes the packet's code index number says are used go to
+                    // the parser, which follows messages (SysEx, or running status sent
+                    // as single bytes) across packets
+                    for &byte in &bytes[1..=packet_len(bytes[0])] {
+                        match parser.feed(byte) {
+                            Some(MidiMessage::Event(event)) => {
+                                forward_event(&mut prod, &mut cc_values, event);
+                            }
+                            Some(MidiMessage::Sysex(msg)) => oxynth_dsp::sysex::handle(msg),
+                            None => {}
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Errors here mean the device has gone away, eg. it's been unplugged
+                    warn!("MIDI wait error: {:?}", e);
+                    break;
                 }
-            }
-            Ok(_) => {}
-            Err(e) => {
-                defmt::warn!("MIDI wait error: {:?}", e);
             }
         }
+
+        info!("USB MIDI device disconnected");
+        // Release anything that was held when the device went away (All Notes Off)
+        enqueue_midi_event(
+            &mut prod,
+            SynthMidiEvent {
+                status: 0xB0,
+                data1: 123,
+                data2: 0,
+            },
+        );
     }
 }